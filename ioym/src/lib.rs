@@ -0,0 +1,857 @@
+//! Decoding of the log files written by loggestd.
+
+use chrono::prelude::*;
+use lazy_static::lazy_static;
+use loggest_protocol::{read_body, Handshake, Record, Value, MAGIC, PREFIX_SIZE};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io::prelude::*;
+use std::io::{self, BufReader, BufWriter, Cursor};
+use thiserror::Error;
+
+pub use loggest_protocol::Precision;
+
+/// The extension of files written by loggestd
+pub const EXT: &str = "ioym";
+
+/// Continuation lines of multi-line messages are indented by the width of the timestamp, see [`indent`]
+const INDENT: &[u8] = b"                              ";
+
+/// Timestamps are milliseconds, so anything beyond the year 9999 is text
+const MAX_TIMESTAMP: u64 = 253_402_300_799_999;
+
+lazy_static! {
+    static ref OFFSET: chrono::FixedOffset = Local::now().offset().fix();
+}
+
+#[derive(Error, Debug)]
+pub enum IoymError {
+    #[error("I/O error: `{0}`")]
+    Io(#[source] io::Error),
+
+    #[error("Unsupported file type for \"`{0}`\"")]
+    UnsupportedFileType(String),
+
+    #[error("Outputting to standard output is not supported with multiple inputs")]
+    StdoutForbidsMultipleInputs,
+
+    #[error("Line has invalid timestamp")]
+    InvalidTimestamp,
+
+    #[error("Unknown fields format \"`{0}`\"")]
+    UnknownFieldsFormat(String),
+
+    #[error("Unknown timestamp precision \"`{0}`\"")]
+    UnknownPrecision(String),
+
+    #[error("Unsupported file version {0}")]
+    UnsupportedVersion(u8),
+
+    #[error("Corrupt record")]
+    CorruptRecord,
+
+    #[error("Version 1 files cannot be merged")]
+    MergeVersion1,
+}
+
+impl From<io::Error> for IoymError {
+    fn from(error: io::Error) -> Self {
+        IoymError::Io(error)
+    }
+}
+
+impl From<loggest_protocol::Error> for IoymError {
+    fn from(error: loggest_protocol::Error) -> Self {
+        match error {
+            loggest_protocol::Error::Io(e) => IoymError::Io(e),
+            loggest_protocol::Error::UnsupportedVersion(version) => IoymError::UnsupportedVersion(version),
+            _ => IoymError::CorruptRecord,
+        }
+    }
+}
+
+pub type IoymResult<T> = Result<T, IoymError>;
+
+/// How structured fields are rendered after the message
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FieldsFormat {
+    KeyValue,
+    Json,
+}
+
+impl std::str::FromStr for FieldsFormat {
+    type Err = IoymError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "kv" => Ok(FieldsFormat::KeyValue),
+            "json" => Ok(FieldsFormat::Json),
+            _ => Err(IoymError::UnknownFieldsFormat(s.to_owned())),
+        }
+    }
+}
+
+/// How records are rendered
+#[derive(Clone, Copy, Debug)]
+struct Render {
+    fields_format: FieldsFormat,
+    /// Show the module path, file and line of records which have them
+    location: bool,
+    /// The fractional seconds shown of timestamps
+    precision: Precision,
+    /// Show the thread of each record, for merged files
+    thread: bool,
+}
+
+impl Default for Render {
+    fn default() -> Self {
+        Self {
+            fields_format: FieldsFormat::KeyValue,
+            location: false,
+            precision: Precision::Millis,
+            thread: false,
+        }
+    }
+}
+
+/// Parse a timestamp precision: `ms`, `us` or `ns`
+pub fn parse_precision(s: &str) -> IoymResult<Precision> {
+    match s {
+        "ms" => Ok(Precision::Millis),
+        "us" => Ok(Precision::Micros),
+        "ns" => Ok(Precision::Nanos),
+        _ => Err(IoymError::UnknownPrecision(s.to_owned())),
+    }
+}
+
+/// Decodes a file written by loggestd, or by the ioym fallback of loggest
+pub struct Ioym<R: BufRead> {
+    input: BufReader<zstd::Decoder<R>>,
+    offset: Option<chrono::FixedOffset>,
+    render: Render,
+}
+
+impl<R: Read> Ioym<BufReader<R>> {
+    pub fn with_reader(r: R) -> IoymResult<Self> {
+        Ok(Self {
+            input: BufReader::new(zstd::Decoder::new(r)?),
+            offset: None,
+            render: Render::default(),
+        })
+    }
+}
+
+#[cfg(test)]
+impl<R: BufRead> Ioym<R> {
+    fn with_buf_reader(r: R) -> IoymResult<Self> {
+        Ok(Self {
+            input: BufReader::new(zstd::Decoder::with_buffer(r)?),
+            offset: None,
+            render: Render::default(),
+        })
+    }
+}
+
+impl<R: BufRead> Ioym<R> {
+    /// Render timestamps in `offset` instead of the local timezone
+    pub fn set_offset(&mut self, offset: chrono::FixedOffset) {
+        self.offset = Some(offset);
+    }
+
+    pub fn set_fields_format(&mut self, fields_format: FieldsFormat) {
+        self.render.fields_format = fields_format;
+    }
+
+    /// Show the module path, file and line of records which have them
+    pub fn set_location(&mut self, location: bool) {
+        self.render.location = location;
+    }
+
+    /// Show the fractional seconds of timestamps with `precision`, padded with zeros beyond those of the file.
+    /// Defaults to milliseconds.
+    pub fn set_precision(&mut self, precision: Precision) {
+        self.render.precision = precision;
+    }
+
+    /// Write the records as text
+    pub fn decode<W: Write>(&mut self, output: &mut W) -> IoymResult<()> {
+        let mut output = BufWriter::with_capacity(zstd::Decoder::<R>::recommended_output_size(), output);
+
+        // Version 2 files start with a header, version 1 files with the timestamp of the first line
+        let mut start = [0; 8];
+        match self.input.read_exact(&mut start) {
+            Err(ref e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            result => result?,
+        }
+
+        if start[..MAGIC.len()] == MAGIC[..] {
+            let mut session = Handshake::read(&mut Cursor::new(start).chain(&mut self.input))?;
+            let offset = self.offset.unwrap_or(*OFFSET);
+            let mut body = Vec::new();
+            while self.read_record(&mut body, &mut session)? {
+                if let Some(record) = Record::decode(&body)? {
+                    write_record(&mut output, &record, &session, offset, self.render)?;
+                }
+            }
+            Ok(())
+        } else {
+            let offset = self.offset.unwrap_or(*OFFSET);
+            decode_lines(
+                &mut Cursor::new(start).chain(&mut self.input),
+                &mut output,
+                offset,
+                self.render,
+            )
+        }
+    }
+
+    /// Read the handshake at the start of a version 2 file, or `None` if it is empty
+    fn read_handshake(&mut self) -> IoymResult<Option<Handshake>> {
+        let mut start = [0; PREFIX_SIZE];
+        match self.input.read_exact(&mut start) {
+            Err(ref e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            result => result?,
+        }
+        if start[..MAGIC.len()] != MAGIC[..] {
+            return Err(IoymError::MergeVersion1);
+        }
+        Ok(Some(Handshake::read(&mut Cursor::new(start).chain(&mut self.input))?))
+    }
+
+    /// Read the body of the next record into `body`, replacing `session` by the handshakes of sessions which follow.
+    /// Returns `false` at the end of the file.
+    fn read_record(&mut self, body: &mut Vec<u8>, session: &mut Handshake) -> IoymResult<bool> {
+        loop {
+            let mut length = [0; 4];
+            match self.input.read_exact(&mut length) {
+                Err(ref e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(false),
+                result => result?,
+            }
+
+            // Files may be concatenated
+            if length[..] == MAGIC[..length.len()] {
+                *session = Handshake::read(&mut Cursor::new(length).chain(&mut self.input))?;
+                continue;
+            }
+
+            match read_body(&mut self.input, u32::from_le_bytes(length), body) {
+                Err(ref e) if e.kind() == io::ErrorKind::InvalidData => return Err(IoymError::CorruptRecord),
+                result => result?,
+            }
+            return Ok(true);
+        }
+    }
+}
+
+/// The position of a record among the merged ones: the nanoseconds since the epoch by the monotonic clock of its
+/// process, then the origin of the process and the sequence number of the record, for records logged in the same
+/// nanosecond. Records of clients without sequence numbers are positioned by their timestamp alone.
+type Order = (u64, u64, u64);
+
+/// A file being merged, with its next record
+struct MergeInput<R: BufRead> {
+    ioym: Ioym<R>,
+    session: Handshake,
+    body: Vec<u8>,
+}
+
+impl<R: BufRead> MergeInput<R> {
+    /// Read the next log record into `body`, returning its order
+    fn next(&mut self) -> IoymResult<Option<Order>> {
+        while self.ioym.read_record(&mut self.body, &mut self.session)? {
+            if let Some(record) = Record::decode(&self.body)? {
+                let origin = self.session.origin;
+                return Ok(Some(match record.sequence {
+                    Some(sequence) => (origin + sequence.monotonic, origin, sequence.number),
+                    None => (
+                        self.session.precision.since_epoch(record.timestamp).as_nanos() as u64,
+                        0,
+                        0,
+                    ),
+                }));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Write the records of several version 2 files as text, in the order they were logged, with the thread of each.
+/// Records are ordered by the monotonic clock of their process, which is immune to adjustments of the wall clock,
+/// and by their sequence numbers within a process. Records of clients without sequence numbers are ordered by
+/// their timestamp.
+pub fn merge<R: BufRead, W: Write>(inputs: Vec<Ioym<R>>, output: &mut W) -> IoymResult<()> {
+    let mut output = BufWriter::new(output);
+    let mut files = Vec::new();
+    for mut ioym in inputs {
+        if let Some(session) = ioym.read_handshake()? {
+            ioym.render.thread = true;
+            files.push(MergeInput {
+                ioym,
+                session,
+                body: Vec::new(),
+            });
+        }
+    }
+
+    // Each file is in order, so only the next record of each is compared
+    let mut next = BinaryHeap::new();
+    for (i, file) in files.iter_mut().enumerate() {
+        if let Some(order) = file.next()? {
+            next.push(Reverse((order, i)));
+        }
+    }
+    while let Some(Reverse((_, i))) = next.pop() {
+        let file = &mut files[i];
+        if let Some(record) = Record::decode(&file.body)? {
+            let offset = file.ioym.offset.unwrap_or(*OFFSET);
+            write_record(&mut output, &record, &file.session, offset, file.ioym.render)?;
+        }
+        if let Some(order) = file.next()? {
+            next.push(Reverse((order, i)));
+        }
+    }
+
+    output.flush()?;
+    Ok(())
+}
+
+fn decode_lines<R: BufRead, W: Write>(
+    input: &mut R,
+    output: &mut W,
+    offset: chrono::FixedOffset,
+    render: Render,
+) -> IoymResult<()> {
+    let mut line = Vec::new();
+    // Bytes read past the end of a short continuation line, which start the next timestamp
+    let mut carry = 0;
+    let mut timestamp = [0; 8];
+
+    loop {
+        let read = carry + read_up_to(input, &mut timestamp[carry..])?;
+        if read < timestamp.len() {
+            // The end of the file, possibly after continuation lines too short to be taken for a timestamp
+            for line in timestamp[..read].split_inclusive(|&b| b == b'\n') {
+                output.write_all(indent(render.precision))?;
+                output.write_all(line)?;
+            }
+            break;
+        }
+        carry = 0;
+
+        let millis = u64::from_le_bytes(timestamp);
+        if millis > MAX_TIMESTAMP {
+            // Version 1 clients wrote multi-line messages as is, so this is the next line of the message
+            output.write_all(indent(render.precision))?;
+            if let Some(end) = memchr::memchr(b'\n', &timestamp) {
+                output.write_all(&timestamp[..=end])?;
+                carry = timestamp.len() - end - 1;
+                timestamp.copy_within(end + 1.., 0);
+                continue;
+            }
+            output.write_all(&timestamp)?;
+        } else if let Ok(ts) = to_time(millis, Precision::Millis, offset) {
+            write_timestamp(output, &ts, render.precision)?;
+        }
+
+        line.clear();
+        input.read_until(b'\n', &mut line)?;
+        output.write_all(&line)?;
+    }
+
+    Ok(())
+}
+
+/// Read until `buf` is full or the input ends, returning the number of bytes read
+fn read_up_to<R: Read>(input: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match input.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+/// The indentation of continuation lines, as wide as timestamps shown with `precision`
+fn indent(precision: Precision) -> &'static [u8] {
+    &INDENT[..21 + precision.digits() as usize]
+}
+
+/// Write a timestamp with the fractional seconds of `precision`, padded with zeros beyond those of the file
+fn write_timestamp<W: Write>(
+    w: &mut W,
+    ts: &chrono::DateTime<FixedOffset>,
+    precision: Precision,
+) -> IoymResult<()> {
+    let digits = precision.digits() as usize;
+    write!(
+        w,
+        "{}-{:02}-{:02} {:02}:{:02}:{:02}.{:0digits$} ",
+        ts.year(),
+        ts.month(),
+        ts.day(),
+        ts.hour(),
+        ts.minute(),
+        ts.second(),
+        ts.nanosecond() / 10u32.pow(9 - digits as u32),
+        digits = digits,
+    )?;
+    Ok(())
+}
+
+fn write_record<W: Write>(
+    w: &mut W,
+    record: &Record,
+    session: &Handshake,
+    offset: chrono::FixedOffset,
+    render: Render,
+) -> IoymResult<()> {
+    if let Ok(ts) = to_time(record.timestamp, session.precision, offset) {
+        write_timestamp(w, &ts, render.precision)?;
+    }
+
+    write!(w, "[{}] ", record.level_name())?;
+    if render.thread {
+        match &session.thread_name {
+            Some(name) => write!(w, "({}) ", name)?,
+            None => write!(w, "({}) ", record.thread_id)?,
+        }
+    }
+    w.write_all(record.target)?;
+    if render.location {
+        write_location(w, record)?;
+    }
+    write_context(w, &record.context)?;
+    w.write_all(b" -- ")?;
+    write_message(w, record.message, render.precision)?;
+    write_fields(w, &record.fields, render.fields_format)?;
+    w.write_all(b"\n")?;
+    Ok(())
+}
+
+/// Write the location as ` [module file:line]`, leaving out the parts which are unknown
+fn write_location<W: Write>(w: &mut W, record: &Record) -> IoymResult<()> {
+    if record.module.is_empty() && record.file.is_empty() {
+        return Ok(());
+    }
+
+    w.write_all(b" [")?;
+    w.write_all(record.module)?;
+    if !record.file.is_empty() {
+        if !record.module.is_empty() {
+            w.write_all(b" ")?;
+        }
+        w.write_all(record.file)?;
+        if record.line != 0 {
+            write!(w, ":{}", record.line)?;
+        }
+    }
+    w.write_all(b"]")?;
+    Ok(())
+}
+
+/// Write the context entries as ` {key=value key=value}`
+fn write_context<W: Write>(w: &mut W, context: &[(&[u8], &[u8])]) -> IoymResult<()> {
+    if context.is_empty() {
+        return Ok(());
+    }
+
+    w.write_all(b" {")?;
+    for (i, (key, value)) in context.iter().enumerate() {
+        if i > 0 {
+            w.write_all(b" ")?;
+        }
+        w.write_all(key)?;
+        w.write_all(b"=")?;
+        w.write_all(value)?;
+    }
+    w.write_all(b"}")?;
+    Ok(())
+}
+
+/// Write a message, indenting its continuation lines
+fn write_message<W: Write>(w: &mut W, message: &[u8], precision: Precision) -> IoymResult<()> {
+    let end = message.iter().rposition(|&b| b != b'\n').map_or(0, |i| i + 1);
+    for (i, line) in message[..end].split(|&b| b == b'\n').enumerate() {
+        if i > 0 {
+            w.write_all(b"\n")?;
+            w.write_all(indent(precision))?;
+        }
+        w.write_all(line)?;
+    }
+    Ok(())
+}
+
+/// Write typed fields, escaping strings as loggest does in version 1 lines
+fn write_fields<W: Write>(w: &mut W, fields: &[(&[u8], Value)], fields_format: FieldsFormat) -> IoymResult<()> {
+    if fields.is_empty() {
+        return Ok(());
+    }
+
+    match fields_format {
+        FieldsFormat::KeyValue => {
+            for (key, value) in fields {
+                w.write_all(b" ")?;
+                w.write_all(key)?;
+                w.write_all(b"=")?;
+                match value {
+                    Value::Str(s) => {
+                        for &b in s.iter() {
+                            match b {
+                                b'\\' => w.write_all(b"\\\\")?,
+                                b'\n' => w.write_all(b"\\n")?,
+                                _ => w.write_all(&[b])?,
+                            }
+                        }
+                    }
+                    Value::I64(v) => write!(w, "{}", v)?,
+                    Value::U64(v) => write!(w, "{}", v)?,
+                    Value::F64(v) => write!(w, "{}", v)?,
+                    Value::Bool(v) => write!(w, "{}", v)?,
+                }
+            }
+        }
+        FieldsFormat::Json => {
+            w.write_all(b" {")?;
+            for (i, (key, value)) in fields.iter().enumerate() {
+                if i > 0 {
+                    w.write_all(b",")?;
+                }
+                write_json_string(w, key)?;
+                w.write_all(b":")?;
+                match value {
+                    Value::Str(s) => write_json_string(w, s)?,
+                    Value::I64(v) => write!(w, "{}", v)?,
+                    Value::U64(v) => write!(w, "{}", v)?,
+                    Value::F64(v) if v.is_finite() => write!(w, "{}", v)?,
+                    Value::F64(v) => write_json_string(w, v.to_string().as_bytes())?,
+                    Value::Bool(v) => write!(w, "{}", v)?,
+                }
+            }
+            w.write_all(b"}")?;
+        }
+    }
+
+    Ok(())
+}
+
+fn write_json_string<W: Write>(w: &mut W, s: &[u8]) -> IoymResult<()> {
+    w.write_all(b"\"")?;
+    for c in String::from_utf8_lossy(s).chars() {
+        match c {
+            '"' => w.write_all(b"\\\"")?,
+            '\\' => w.write_all(b"\\\\")?,
+            '\n' => w.write_all(b"\\n")?,
+            c if (c as u32) < 0x20 => write!(w, "\\u{:04x}", c as u32)?,
+            c => write!(w, "{}", c)?,
+        }
+    }
+    w.write_all(b"\"")?;
+    Ok(())
+}
+
+fn to_time(
+    timestamp: u64,
+    precision: Precision,
+    offset: chrono::FixedOffset,
+) -> IoymResult<chrono::DateTime<FixedOffset>> {
+    let duration = precision.since_epoch(timestamp);
+    match offset.timestamp_opt(duration.as_secs() as i64, duration.subsec_nanos()) {
+        chrono::offset::LocalResult::Single(timestamp) => Ok(timestamp),
+        _ => Err(IoymError::InvalidTimestamp),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{Offset, Utc};
+    use std::io::Cursor;
+
+    #[test]
+    fn test_ioym_decode() {
+        let compressed = include_bytes!("../samples/sample.ioym").to_vec();
+        let sample_output = include_bytes!("../samples/sample").to_vec();
+
+        let mut ioym = super::Ioym::with_buf_reader(Cursor::new(compressed)).unwrap();
+        ioym.set_offset(Utc.fix());
+        let mut output = Vec::new();
+        ioym.decode(&mut output).unwrap();
+        assert_eq!(output, sample_output);
+    }
+
+    fn str16(buf: &mut Vec<u8>, s: &str) {
+        buf.extend_from_slice(&(s.len() as u16).to_le_bytes());
+        buf.extend_from_slice(s.as_bytes());
+    }
+
+    /// A version 2 file header for `filename`
+    fn v2_header(filename: &str) -> Vec<u8> {
+        let mut header = loggest_protocol::MAGIC.to_vec();
+        header.push(loggest_protocol::VERSION);
+        header.extend_from_slice(&(3 + filename.len() as u32).to_le_bytes());
+        header.push(1);
+        str16(&mut header, filename);
+        header
+    }
+
+    /// A version 2 log record at info level, with an `i64` field
+    fn v2_record(timestamp: u64, message: &str, field: Option<(&str, i64)>) -> Vec<u8> {
+        let mut body = vec![1];
+        body.extend_from_slice(&timestamp.to_le_bytes());
+        body.push(3);
+        body.extend_from_slice(&1234u64.to_le_bytes());
+        body.extend_from_slice(&42u32.to_le_bytes());
+        str16(&mut body, "test");
+        str16(&mut body, "test::module");
+        str16(&mut body, "src/test.rs");
+        body.extend_from_slice(&(message.len() as u32).to_le_bytes());
+        body.extend_from_slice(message.as_bytes());
+        if let Some((key, value)) = field {
+            body.push(1);
+            body.extend_from_slice(&(2 + key.len() as u32 + 9).to_le_bytes());
+            str16(&mut body, key);
+            body.push(2);
+            body.extend_from_slice(&value.to_le_bytes());
+        }
+
+        let mut record = (body.len() as u32).to_le_bytes().to_vec();
+        record.extend_from_slice(&body);
+        record
+    }
+
+    fn decode(data: &[u8], render: super::Render) -> String {
+        let compressed = zstd::stream::encode_all(data, 1).unwrap();
+        let mut ioym = super::Ioym::with_buf_reader(Cursor::new(compressed)).unwrap();
+        ioym.set_offset(Utc.fix());
+        ioym.render = render;
+        let mut output = Vec::new();
+        ioym.decode(&mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_ioym_decode_v2() {
+        let mut data = v2_header("test");
+        data.extend(v2_record(1_528_377_136_413, "Running command 0", Some(("node_id", -4))));
+        data.extend(v2_record(1_528_377_136_414, "Starting up", None));
+        // A second header, as in concatenated files
+        data.extend(v2_header("test"));
+        data.extend(v2_record(1_528_377_136_415, "Done", None));
+
+        assert_eq!(
+            decode(&data, Default::default()),
+            "2018-06-07 13:12:16.413 [INFO] test -- Running command 0 node_id=-4\n\
+             2018-06-07 13:12:16.414 [INFO] test -- Starting up\n\
+             2018-06-07 13:12:16.415 [INFO] test -- Done\n"
+        );
+        assert!(decode(
+            &data,
+            super::Render {
+                fields_format: super::FieldsFormat::Json,
+                ..Default::default()
+            }
+        )
+        .starts_with("2018-06-07 13:12:16.413 [INFO] test -- Running command 0 {\"node_id\":-4}\n"));
+    }
+
+    #[test]
+    fn test_corrupt_length() {
+        let mut data = v2_header("test");
+        data.extend(v2_record(1_528_377_136_413, "Starting up", None));
+        data.extend_from_slice(&u32::MAX.to_le_bytes());
+        data.extend_from_slice(b"garbage");
+
+        let compressed = zstd::stream::encode_all(&data[..], 1).unwrap();
+        let mut ioym = super::Ioym::with_buf_reader(Cursor::new(compressed)).unwrap();
+        let mut output = Vec::new();
+        assert!(matches!(ioym.decode(&mut output), Err(super::IoymError::CorruptRecord)));
+    }
+
+    #[test]
+    fn test_multiline_message() {
+        let mut data = v2_header("test");
+        data.extend(v2_record(
+            1_528_377_136_413,
+            "Backtrace:\n  0: main\n  1: start\n",
+            None,
+        ));
+        data.extend(v2_record(1_528_377_136_414, "Next", None));
+
+        assert_eq!(
+            decode(&data, Default::default()),
+            "2018-06-07 13:12:16.413 [INFO] test -- Backtrace:\n\
+             \x20                         0: main\n\
+             \x20                         1: start\n\
+             2018-06-07 13:12:16.414 [INFO] test -- Next\n"
+        );
+
+        // Version 1 files have the message as is, followed by the timestamp of the next line
+        let mut data = 1_528_377_136_413u64.to_le_bytes().to_vec();
+        data.extend_from_slice(b"[INFO] test -- Config {\n    name: \"test\",\n}\n");
+        data.extend_from_slice(&1_528_377_136_414u64.to_le_bytes());
+        data.extend_from_slice(b"[INFO] test -- Next\n");
+
+        assert_eq!(
+            decode(&data, Default::default()),
+            "2018-06-07 13:12:16.413 [INFO] test -- Config {\n\
+             \x20                           name: \"test\",\n\
+             \x20                       }\n\
+             2018-06-07 13:12:16.414 [INFO] test -- Next\n"
+        );
+
+        // Ending the file with continuation lines shorter than a timestamp
+        let mut data = 1_528_377_136_413u64.to_le_bytes().to_vec();
+        data.extend_from_slice(b"[INFO] test -- Config {\n a,\n}\n");
+
+        assert_eq!(
+            decode(&data, Default::default()),
+            "2018-06-07 13:12:16.413 [INFO] test -- Config {\n\
+             \x20                        a,\n\
+             \x20                       }\n"
+        );
+    }
+
+    #[test]
+    fn test_location() {
+        let mut data = v2_header("test");
+        data.extend(v2_record(1_528_377_136_413, "Starting up", None));
+
+        assert_eq!(
+            decode(
+                &data,
+                super::Render {
+                    location: true,
+                    ..Default::default()
+                }
+            ),
+            "2018-06-07 13:12:16.413 [INFO] test [test::module src/test.rs:42] -- Starting up\n"
+        );
+    }
+
+    #[test]
+    fn test_context() {
+        let mut data = v2_header("test");
+        loggest_protocol::Record {
+            timestamp: 1_528_377_136_413,
+            level: 3,
+            target: b"test",
+            message: b"Handling",
+            context: vec![(b"req_id", b"f3a9"), (b"user", b"7")],
+            ..Default::default()
+        }
+        .encode(&mut data);
+
+        assert_eq!(
+            decode(&data, Default::default()),
+            "2018-06-07 13:12:16.413 [INFO] test {req_id=f3a9 user=7} -- Handling\n"
+        );
+    }
+
+    #[test]
+    fn test_precision() {
+        let mut data = Vec::new();
+        loggest_protocol::Handshake {
+            filename: "test".to_owned(),
+            precision: super::Precision::Micros,
+            ..Default::default()
+        }
+        .encode(&mut data);
+        loggest_protocol::Record {
+            timestamp: 1_528_377_136_413_250,
+            level: 3,
+            target: b"test",
+            message: b"Backtrace:\n  0: main",
+            ..Default::default()
+        }
+        .encode(&mut data);
+        // Older sessions of the same file are in milliseconds
+        data.extend(v2_header("test"));
+        data.extend(v2_record(1_528_377_136_414, "Done", None));
+
+        assert_eq!(
+            decode(&data, Default::default()),
+            "2018-06-07 13:12:16.413 [INFO] test -- Backtrace:\n\
+             \x20                         0: main\n\
+             2018-06-07 13:12:16.414 [INFO] test -- Done\n"
+        );
+        assert_eq!(
+            decode(
+                &data,
+                super::Render {
+                    precision: super::Precision::Nanos,
+                    ..Default::default()
+                }
+            ),
+            "2018-06-07 13:12:16.413250000 [INFO] test -- Backtrace:\n\
+             \x20                               0: main\n\
+             2018-06-07 13:12:16.414000000 [INFO] test -- Done\n"
+        );
+    }
+
+    /// Milliseconds since the epoch, at which the processes of `test_merge` start
+    const START: u64 = 1_528_377_136_000;
+
+    /// A file of the thread `thread` with records logged at the given milliseconds since `START`. With the sequence
+    /// numbers of the records if `origin` is the milliseconds since `START` at which their process started, as
+    /// written by a client without sequence numbers otherwise.
+    fn merged_file(thread: &str, origin: Option<u64>, records: &[(u64, u64)]) -> super::Ioym<Cursor<Vec<u8>>> {
+        let nanos = |millis| (START + millis) * 1_000_000;
+        let mut data = Vec::new();
+        loggest_protocol::Handshake {
+            filename: "test".to_owned(),
+            thread_name: Some(thread.to_owned()),
+            origin: origin.map_or(0, nanos),
+            ..Default::default()
+        }
+        .encode(&mut data);
+        for &(millis, number) in records {
+            let message = format!("Record {}", number);
+            loggest_protocol::Record {
+                timestamp: START + millis,
+                level: 3,
+                target: b"test",
+                message: message.as_bytes(),
+                sequence: origin.map(|origin| loggest_protocol::Sequence {
+                    number,
+                    monotonic: (millis - origin) * 1_000_000,
+                }),
+                ..Default::default()
+            }
+            .encode(&mut data);
+        }
+
+        let compressed = zstd::stream::encode_all(&data[..], 1).unwrap();
+        let mut ioym = super::Ioym::with_buf_reader(Cursor::new(compressed)).unwrap();
+        ioym.set_offset(Utc.fix());
+        ioym
+    }
+
+    #[test]
+    fn test_merge() {
+        let inputs = vec![
+            merged_file("main", Some(0), &[(0, 0), (30, 3)]),
+            // Records 1 and 2 are logged in the same millisecond
+            merged_file("worker", Some(0), &[(10, 1), (10, 2), (40, 4)]),
+            // A child forked by the process, whose sequence starts over
+            merged_file("child", Some(15), &[(15, 0), (35, 1)]),
+            merged_file("old", None, &[(25, 0)]),
+        ];
+        let mut output = Vec::new();
+        super::merge(inputs, &mut output).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "2018-06-07 13:12:16.000 [INFO] (main) test -- Record 0\n\
+             2018-06-07 13:12:16.010 [INFO] (worker) test -- Record 1\n\
+             2018-06-07 13:12:16.010 [INFO] (worker) test -- Record 2\n\
+             2018-06-07 13:12:16.015 [INFO] (child) test -- Record 0\n\
+             2018-06-07 13:12:16.025 [INFO] (old) test -- Record 0\n\
+             2018-06-07 13:12:16.030 [INFO] (main) test -- Record 3\n\
+             2018-06-07 13:12:16.035 [INFO] (child) test -- Record 1\n\
+             2018-06-07 13:12:16.040 [INFO] (worker) test -- Record 4\n"
+        );
+    }
+}
@@ -1,44 +1,11 @@
-use byteorder::{ReadBytesExt, LE};
 use chrono::prelude::*;
-use lazy_static::lazy_static;
+use ioym::{merge, parse_precision, FieldsFormat, Ioym, IoymError, IoymResult, Precision, EXT};
 use rayon::prelude::*;
 use std::ffi::OsStr;
 use std::fs;
-use std::io::prelude::*;
-use std::io::{self, BufReader};
+use std::io::BufReader;
 use std::path::{Path, PathBuf};
-use std::time::Duration;
 use structopt::StructOpt;
-use thiserror::Error;
-
-const EXT: &str = "ioym";
-
-lazy_static! {
-    static ref OFFSET: chrono::FixedOffset = Local::now().offset().fix();
-}
-
-#[derive(Error, Debug)]
-enum IoymError {
-    #[error("I/O error: `{0}`")]
-    Io(#[source] io::Error),
-
-    #[error("Unsupported file type for \"`{0}`\"")]
-    UnsupportedFileType(String),
-
-    #[error("Outputting to standard output is not supported with multiple inputs")]
-    StdoutForbidsMultipleInputs,
-
-    #[error("Line has invalid timestamp")]
-    InvalidTimestamp,
-}
-
-impl From<io::Error> for IoymError {
-    fn from(error: io::Error) -> Self {
-        IoymError::Io(error)
-    }
-}
-
-type IoymResult<T> = Result<T, IoymError>;
 
 #[derive(Clone, Copy)]
 enum Output {
@@ -46,116 +13,25 @@ enum Output {
     File,
 }
 
-struct Ioym<R: BufRead> {
-    input: BufReader<zstd::Decoder<R>>,
-    offset: Option<chrono::FixedOffset>,
-}
-
-impl<R: Read> Ioym<BufReader<R>> {
-    fn with_reader(r: R) -> IoymResult<Self> {
-        Ok(Self {
-            input: BufReader::new(zstd::Decoder::new(r)?),
-            offset: None,
-        })
-    }
-}
-
-#[cfg(test)]
-impl<R: BufRead> Ioym<R> {
-    fn with_buf_reader(r: R) -> IoymResult<Self> {
-        Ok(Self {
-            input: BufReader::new(zstd::Decoder::with_buffer(r)?),
-            offset: None,
-        })
-    }
-}
-
-impl<R: BufRead> Ioym<R> {
-    fn set_offset(&mut self, offset: chrono::FixedOffset) {
-        self.offset = Some(offset);
-    }
-
-    fn decode<W: Write>(&mut self, output: &mut W) -> IoymResult<()> {
-        let mut output = std::io::BufWriter::with_capacity(zstd::Decoder::<R>::recommended_output_size(), output);
-
-        loop {
-            match read_time(&mut self.input, self.offset.unwrap_or(*OFFSET)) {
-                Err(IoymError::Io(ref e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
-                Err(IoymError::InvalidTimestamp) => (),
-                Err(e) => return Err(e),
-                Ok(ts) => write!(
-                    &mut output,
-                    "{}-{:02}-{:02} {:02}:{:02}:{:02}.{:03} ",
-                    ts.year(),
-                    ts.month(),
-                    ts.day(),
-                    ts.hour(),
-                    ts.minute(),
-                    ts.second(),
-                    ts.nanosecond() / 1_000_000,
-                )?,
-            };
-
-            copy_until(&mut self.input, &mut output, b'\n')?;
-        }
-
-        Ok(())
-    }
-}
-
-/// Read all bytes into `w` until the delimiter `byte` or EOF is reached.
-///
-/// Copied from [`BufRead::read_until`] and modified to write to `Write` instead of `Vec<u8>`.
-fn copy_until<R, W>(r: &mut R, w: &mut W, delim: u8) -> IoymResult<usize>
-where
-    R: BufRead,
-    W: Write,
-{
-    let mut read = 0;
-    loop {
-        let (done, used) = {
-            let available = match r.fill_buf() {
-                Ok(n) => n,
-                Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
-                Err(e) => return Err(e.into()),
-            };
-            match memchr::memchr(delim, available) {
-                Some(i) => {
-                    w.write_all(&available[..=i])?;
-                    (true, i + 1)
-                }
-                None => {
-                    w.write_all(available)?;
-                    (false, available.len())
-                }
-            }
-        };
-        r.consume(used);
-        read += used;
-        if done || used == 0 {
-            return Ok(read);
-        }
-    }
-}
-
-fn read_time<R: BufRead>(input: &mut R, offset: chrono::FixedOffset) -> IoymResult<chrono::DateTime<FixedOffset>> {
-    let duration = Duration::from_millis(input.read_u64::<LE>()?);
-    match offset.timestamp_opt(duration.as_secs() as i64, duration.subsec_nanos()) {
-        chrono::offset::LocalResult::Single(timestamp) => Ok(timestamp),
-        _ => Err(IoymError::InvalidTimestamp),
-    }
-}
-
-fn handle_file(filename: &Path, output: Output, is_utc: bool) -> IoymResult<()> {
+/// Open a file to decode with the options
+fn open(filename: &Path, opt: &Opt) -> IoymResult<Ioym<BufReader<fs::File>>> {
     if filename.extension() != Some(OsStr::new(EXT)) {
         return Err(IoymError::UnsupportedFileType(filename.to_string_lossy().to_string()));
     }
 
     let mut ioym = Ioym::with_reader(fs::File::open(filename)?)?;
 
-    if is_utc {
+    if opt.utc {
         ioym.set_offset(Utc.fix());
     }
+    ioym.set_fields_format(opt.fields);
+    ioym.set_location(opt.location);
+    ioym.set_precision(opt.precision);
+    Ok(ioym)
+}
+
+fn handle_file(filename: &Path, output: Output, opt: &Opt) -> IoymResult<()> {
+    let mut ioym = open(filename, opt)?;
 
     match output {
         Output::Stdout => {
@@ -188,6 +64,23 @@ struct Opt {
     /// Use UTC instead of local timezone
     utc: bool,
 
+    #[structopt(long, default_value = "kv")]
+    /// Render structured fields as `kv` (key=value) or `json`
+    fields: FieldsFormat,
+
+    #[structopt(long, short)]
+    /// Show the module path, file and line of each record, when known
+    location: bool,
+
+    #[structopt(long, short, default_value = "ms", parse(try_from_str = parse_precision))]
+    /// Show the fractional seconds of timestamps as `ms`, `us` or `ns`
+    precision: Precision,
+
+    #[structopt(long, short)]
+    /// Merge the files, e.g. of all the threads of a process, to standard output in the order their records were
+    /// logged
+    merge: bool,
+
     #[structopt(parse(from_os_str), required = true)]
     files: Vec<PathBuf>,
 }
@@ -195,19 +88,22 @@ struct Opt {
 fn run() -> IoymResult<()> {
     let opt = Opt::from_args();
 
+    if opt.merge {
+        let inputs = opt
+            .files
+            .iter()
+            .map(|filename| open(filename, &opt))
+            .collect::<Result<Vec<_>, _>>()?;
+        return merge(inputs, &mut std::io::stdout().lock());
+    }
+
     if opt.stdout && opt.files.len() > 1 {
         return Err(IoymError::StdoutForbidsMultipleInputs);
     }
 
     opt.files
         .par_iter()
-        .map(|filename| {
-            handle_file(
-                filename,
-                if opt.stdout { Output::Stdout } else { Output::File },
-                opt.utc,
-            )
-        })
+        .map(|filename| handle_file(filename, if opt.stdout { Output::Stdout } else { Output::File }, &opt))
         .collect::<Result<Vec<_>, _>>()?;
 
     Ok(())
@@ -219,21 +115,3 @@ fn main() {
         std::process::exit(1);
     }
 }
-
-#[cfg(test)]
-mod test {
-    use chrono::{Offset, Utc};
-    use std::io::Cursor;
-
-    #[test]
-    fn test_ioym_decode() {
-        let compressed = include_bytes!("../samples/sample.ioym").to_vec();
-        let sample_output = include_bytes!("../samples/sample").to_vec();
-
-        let mut ioym = super::Ioym::with_buf_reader(Cursor::new(compressed)).unwrap();
-        ioym.set_offset(Utc.fix());
-        let mut output = Vec::new();
-        ioym.decode(&mut output).unwrap();
-        assert_eq!(output, sample_output);
-    }
-}
@@ -16,6 +16,7 @@ cargo build --release --target %{_TARGET} %{?_CARGO_BUILD_ARGS}
 
 %install
 install -D -m 755 %{_sourcedir}/target/%{_TARGET}/release/%{name} -t %{buildroot}%{_bindir}
+install -D -m 755 %{_sourcedir}/target/%{_TARGET}/release/loggestctl -t %{buildroot}%{_bindir}
 install -D -m 755 %{_sourcedir}/loggestd.service -t %{buildroot}%{_unitdir}
 
 %post
@@ -0,0 +1,82 @@
+//! Controls the processes logging to loggestd
+
+use loggest_protocol::{read_frame, Handshake, Reply};
+use std::io::{self, Read, Write};
+#[cfg(windows)]
+use std::net::{SocketAddr, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::process::exit;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(about)]
+struct Opt {
+    /// Unix socket of loggestd
+    #[cfg(unix)]
+    #[structopt(
+        long,
+        parse(from_os_str),
+        default_value = "/run/loggestd.sock",
+        env = "LOGGESTD_SOCKET"
+    )]
+    unix_socket: PathBuf,
+
+    /// Address of loggestd
+    #[cfg(windows)]
+    #[structopt(long, default_value = "127.0.0.1:1099", env = "LOGGESTD_LISTEN")]
+    listen: SocketAddr,
+
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Change the filters of the processes logging to a base file name, which enabled remote control
+    SetFilter {
+        /// Base file name of the processes
+        filename: String,
+
+        /// Filter directives, e.g. `info,mycrate::db=debug`
+        directives: String,
+    },
+}
+
+fn set_filter<T: Read + Write>(mut transport: T, filename: &str, directives: &str) -> io::Result<u32> {
+    Handshake {
+        filename: filename.to_owned(),
+        set_filters: Some(directives.to_owned()),
+        ..Default::default()
+    }
+    .write(&mut transport)?;
+
+    let mut reply = Vec::new();
+    read_frame(&mut transport, &mut reply)?;
+    match Reply::decode(&reply) {
+        Ok(Some(Reply::Sent(sent))) => Ok(sent),
+        _ => Err(io::Error::other("Unexpected reply from loggestd")),
+    }
+}
+
+fn main() {
+    let opt = Opt::from_args();
+
+    #[cfg(unix)]
+    let transport = UnixStream::connect(&opt.unix_socket);
+    #[cfg(windows)]
+    let transport = TcpStream::connect(opt.listen);
+
+    let result = transport.and_then(|transport| match &opt.command {
+        Command::SetFilter { filename, directives } => set_filter(transport, filename, directives),
+    });
+    match result {
+        Ok(sent) => println!("Sent to {} processes", sent),
+        Err(e) => {
+            eprintln!("Failed to reach loggestd: {}", e);
+            exit(1);
+        }
+    }
+}
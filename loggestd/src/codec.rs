@@ -1,6 +1,7 @@
-use byteorder::{BigEndian, ByteOrder};
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
 use bytes::{Bytes, BytesMut};
 use log::trace;
+use loggest_protocol::{Handshake, SyncRequest, MAGIC, MAX_FRAME_SIZE};
 use std::io;
 use std::path::PathBuf;
 use std::str::from_utf8;
@@ -8,15 +9,138 @@ use tokio::codec::Decoder;
 
 const LENGTH_SIZE: usize = 2;
 
+const RECORD_LENGTH_SIZE: usize = 4;
+
 #[derive(Debug)]
 pub enum LoggestdData {
     FileName(PathBuf),
+    /// A version 2 session, with its handshake to be written at the start of its files
+    Session {
+        filename: PathBuf,
+        thread_name: Option<String>,
+        header: Bytes,
+    },
+    /// A process listening for filter changes
+    Listener(PathBuf),
+    /// Send filter directives to the processes listening with the file name
+    SetFilters {
+        filename: PathBuf,
+        directives: String,
+    },
     FileData(Bytes),
+    /// Acknowledge once the preceding data is written, and fsynced if durable
+    Sync {
+        id: u64,
+        durable: bool,
+    },
+}
+
+#[derive(Debug, Default, PartialEq)]
+enum State {
+    #[default]
+    Handshake,
+    /// Version 1: an unframed stream
+    Stream,
+    /// Version 2: length-prefixed records
+    Records,
+    /// Version 2 listeners and `loggestctl`, which only receive replies
+    Control,
 }
 
 #[derive(Default, Debug)]
 pub struct LoggestdCodec {
-    sending_data: bool,
+    state: State,
+}
+
+fn validate_filename(buf: &[u8]) -> Result<PathBuf, io::Error> {
+    let filename = from_utf8(buf).map_err(io::Error::other).map(PathBuf::from)?;
+    if filename.parent().filter(|s| !s.as_os_str().is_empty()).is_some() {
+        return Err(io::Error::other(format!("Invalid file name {}:", filename.display())));
+    }
+
+    Ok(filename)
+}
+
+impl LoggestdCodec {
+    fn decode_handshake(&mut self, src: &mut BytesMut) -> Result<Option<LoggestdData>, io::Error> {
+        if src.len() < LENGTH_SIZE {
+            return Ok(None);
+        }
+
+        if src[..LENGTH_SIZE] == MAGIC[..LENGTH_SIZE] {
+            let (handshake, length) = match Handshake::decode(src).map_err(io::Error::other)? {
+                Some(decoded) => decoded,
+                None => return Ok(None),
+            };
+            let header = src.split_to(length).freeze();
+            let filename = validate_filename(handshake.filename.as_bytes())?;
+
+            return Ok(Some(match handshake {
+                Handshake {
+                    set_filters: Some(directives),
+                    ..
+                } => {
+                    self.state = State::Control;
+                    LoggestdData::SetFilters { filename, directives }
+                }
+                Handshake { control: true, .. } => {
+                    self.state = State::Control;
+                    LoggestdData::Listener(filename)
+                }
+                Handshake { thread_name, .. } => {
+                    self.state = State::Records;
+                    LoggestdData::Session {
+                        filename,
+                        thread_name,
+                        header,
+                    }
+                }
+            }));
+        }
+
+        let filename_length = BigEndian::read_u16(src.as_ref()) as usize;
+        if src.len() >= filename_length + LENGTH_SIZE {
+            src.split_to(LENGTH_SIZE);
+            let buf = src.split_to(filename_length);
+            let filename = validate_filename(&buf)?;
+
+            self.state = State::Stream;
+            Ok(Some(LoggestdData::FileName(filename)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Take all of the complete records up to the first sync, leaving a partial one in `src`
+    fn decode_records(&mut self, src: &mut BytesMut) -> Result<Option<LoggestdData>, io::Error> {
+        let mut complete = 0;
+        while src.len() >= complete + RECORD_LENGTH_SIZE {
+            let length = LittleEndian::read_u32(&src[complete..]) as usize;
+            if length > MAX_FRAME_SIZE {
+                return Err(io::Error::other(format!("Record of {} bytes is too large", length)));
+            }
+            if src.len() < complete + RECORD_LENGTH_SIZE + length {
+                break;
+            }
+
+            let body = &src[complete + RECORD_LENGTH_SIZE..complete + RECORD_LENGTH_SIZE + length];
+            if let Some(SyncRequest { id, durable }) = SyncRequest::decode(body) {
+                if complete > 0 {
+                    // The records before it are taken first
+                    break;
+                }
+                src.split_to(RECORD_LENGTH_SIZE + length);
+                return Ok(Some(LoggestdData::Sync { id, durable }));
+            }
+            complete += RECORD_LENGTH_SIZE + length;
+        }
+
+        Ok(if complete == 0 {
+            None
+        } else {
+            Some(LoggestdData::FileData(src.split_to(complete).freeze()))
+        })
+    }
 }
 
 impl Decoder for LoggestdCodec {
@@ -25,41 +149,28 @@ impl Decoder for LoggestdCodec {
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
         trace!("{:?}", src);
-        if !self.sending_data {
-            let filename_length = {
-                if src.len() < LENGTH_SIZE {
-                    return Ok(None);
+        match self.state {
+            State::Handshake => self.decode_handshake(src),
+            State::Records => self.decode_records(src),
+            State::Control => {
+                if src.is_empty() {
+                    Ok(None)
+                } else {
+                    Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Control sessions cannot send records",
+                    ))
                 }
-                BigEndian::read_u16(src.as_ref()) as usize
-            };
-
-            if src.len() >= filename_length + LENGTH_SIZE {
-                src.split_to(LENGTH_SIZE);
-                let buf = src.split_to(filename_length);
-
-                let filename = from_utf8(&buf)
-                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
-                    .map(PathBuf::from)?;
-                if filename.parent().filter(|s| !s.as_os_str().is_empty()).is_some() {
-                    return Err(io::Error::new(
-                        io::ErrorKind::Other,
-                        format!("Invalid file name {}:", filename.display()),
-                    ));
-                }
-
-                self.sending_data = true;
-                Ok(Some(LoggestdData::FileName(filename)))
-            } else {
-                Ok(None)
             }
-        } else {
-            let buf = src.take();
+            State::Stream => {
+                let buf = src.take();
 
-            Ok(if buf.is_empty() {
-                None
-            } else {
-                Some(LoggestdData::FileData(buf.freeze()))
-            })
+                Ok(if buf.is_empty() {
+                    None
+                } else {
+                    Some(LoggestdData::FileData(buf.freeze()))
+                })
+            }
         }
     }
 }
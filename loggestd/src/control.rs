@@ -0,0 +1,45 @@
+//! Processes listening for filter changes, to which `loggestctl set-filter` sends directives.
+
+use futures::sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+#[derive(Default)]
+struct Inner {
+    next_id: u64,
+    listeners: HashMap<u64, (PathBuf, UnboundedSender<String>)>,
+}
+
+#[derive(Clone, Default)]
+pub struct Listeners {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Listeners {
+    /// Register a process logging to `filename`, returning its ID and the directives sent to it
+    pub fn register(&self, filename: PathBuf) -> (u64, UnboundedReceiver<String>) {
+        let (sender, receiver) = unbounded();
+        let mut inner = self.inner.lock().unwrap();
+        inner.next_id += 1;
+        let id = inner.next_id;
+        inner.listeners.insert(id, (filename, sender));
+        (id, receiver)
+    }
+
+    pub fn unregister(&self, id: u64) {
+        self.inner.lock().unwrap().listeners.remove(&id);
+    }
+
+    /// Send directives to the processes logging to `filename`, returning how many there are
+    pub fn send(&self, filename: &Path, directives: &str) -> usize {
+        self.inner
+            .lock()
+            .unwrap()
+            .listeners
+            .values()
+            .filter(|(f, _)| f == filename)
+            .filter(|(_, sender)| sender.unbounded_send(directives.to_owned()).is_ok())
+            .count()
+    }
+}
@@ -0,0 +1,132 @@
+//! The loggestd daemon as a library, e.g. to run it in the integration tests of processes using `loggest`.
+//!
+//! # Example
+//! ```no_run
+//! let server = loggestd::Server::bind("/tmp/logs", "/tmp/loggestd.sock").unwrap();
+//! // Log with `loggest::Builder::new("my-test").socket("/tmp/loggestd.sock")`
+//! server.shutdown();
+//! ```
+
+use futures::sync::oneshot;
+use log::{error, info};
+use std::fmt::Debug;
+use std::io;
+#[cfg(windows)]
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+#[cfg(unix)]
+use tokio::net::unix::UnixListener;
+#[cfg(windows)]
+use tokio::net::TcpListener;
+use tokio::prelude::*;
+use tokio::runtime::Runtime;
+
+mod codec;
+mod control;
+mod log_file;
+mod session;
+mod usage_monitor;
+
+/// A running loggestd, which stops when shut down or dropped
+pub struct Server {
+    runtime: Option<Runtime>,
+    stop: Option<oneshot::Sender<()>>,
+    #[cfg(unix)]
+    socket: PathBuf,
+}
+
+impl Server {
+    /// Listen on the unix socket `socket`, and write the logs to `directory`
+    #[cfg(unix)]
+    pub fn bind<P, S>(directory: P, socket: S) -> Result<Self, io::Error>
+    where
+        P: Into<PathBuf>,
+        S: AsRef<Path>,
+    {
+        let listener = UnixListener::bind(socket.as_ref())?;
+        let (runtime, stop) = run(directory.into(), listener.incoming())?;
+        Ok(Self {
+            runtime: Some(runtime),
+            stop: Some(stop),
+            socket: socket.as_ref().to_owned(),
+        })
+    }
+
+    /// Listen on the TCP address `address`, and write the logs to `directory`
+    #[cfg(windows)]
+    pub fn bind<P>(directory: P, address: &SocketAddr) -> Result<Self, io::Error>
+    where
+        P: Into<PathBuf>,
+    {
+        let listener = TcpListener::bind(address)?;
+        let (runtime, stop) = run(directory.into(), listener.incoming())?;
+        Ok(Self {
+            runtime: Some(runtime),
+            stop: Some(stop),
+        })
+    }
+
+    /// Stop accepting sessions and close the open ones, archiving their files. Records which were not synced by
+    /// their clients may be lost.
+    pub fn shutdown(mut self) {
+        self.stop();
+    }
+
+    fn stop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            stop.send(()).ok();
+        }
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_now().wait().ok();
+            #[cfg(unix)]
+            std::fs::remove_file(&self.socket).ok();
+        }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Start a runtime accepting the sessions of `incoming`, which stops accepting them once signaled
+fn run<S>(directory: PathBuf, incoming: S) -> Result<(Runtime, oneshot::Sender<()>), io::Error>
+where
+    S: Stream<Error = io::Error> + Send + 'static,
+    S::Item: AsyncRead + AsyncWrite + Debug + Send + 'static,
+{
+    info!("Logging to {}", directory.display());
+
+    let (stop, stopped) = oneshot::channel();
+    let directory = Arc::new(directory);
+    let listeners = control::Listeners::default();
+    let server = incoming
+        .for_each({
+            let directory = directory.clone();
+            move |socket| {
+                info!("Connected: {:?}", socket);
+                let session = session::LoggestdSession::new(socket, directory.clone(), listeners.clone());
+                tokio::spawn(session.map_err(|e| {
+                    error!("Session error: {}", e);
+                }));
+                Ok(())
+            }
+        })
+        .map_err(|e| {
+            error!("Error accepting: {:?}", e);
+        })
+        .select(stopped.map_err(|_| ()))
+        .map(|_| ())
+        .map_err(|_| ());
+
+    let mut runtime = Runtime::new()?;
+    runtime.spawn(server);
+    runtime.spawn(usage_monitor::UsageMonitor::new(&directory).map_err(|e| {
+        error!("Usage monitor error: {}", e);
+    }));
+    Ok((runtime, stop))
+}
@@ -1,5 +1,6 @@
 use bytes::Bytes;
 use log::info;
+use loggest_protocol::{archived_filename, generate_filename, next_free_index};
 use std::fs::{create_dir_all, rename, File};
 use std::io;
 use std::path::{Path, PathBuf};
@@ -11,52 +12,57 @@ const ARCHIVE_THREASHOLD: usize = 1024 * 1024 * 1024;
 pub struct LogFile {
     file: File,
     base_filename: PathBuf,
+    /// Written at the start of every file
+    header: Option<Bytes>,
     consumed_data: usize,
     index: usize,
 }
 
-fn generate_filename(base_name: &Path, index: usize) -> PathBuf {
-    let mut path = PathBuf::from(base_name);
-
-    let new_filename = format!("{}.{:02}.ioym", path.file_name().unwrap().to_str().unwrap(), index);
-    path.set_file_name(new_filename);
-    path
-}
-
 impl LogFile {
-    pub fn open(base_filename: PathBuf) -> Result<Self, io::Error> {
-        let index = 1;
+    pub fn open(base_filename: PathBuf, header: Option<Bytes>) -> Result<Self, io::Error> {
+        let index = next_free_index(&base_filename, 1);
         let filename = generate_filename(&base_filename, index);
 
         create_dir_all(filename.parent().unwrap())?;
         let file = File::create(&filename)?;
 
         info!("Opened {}", filename.display());
-        Ok(LogFile {
+        let mut log_file = LogFile {
             file,
             base_filename,
+            header,
             consumed_data: 0,
             index,
-        })
+        };
+        log_file.write_header()?;
+        Ok(log_file)
     }
 
-    fn archive(filename: &Path) -> Result<(), io::Error> {
-        let archive_directory = filename.parent().unwrap().join("archived");
-        create_dir_all(&archive_directory)?;
+    fn write_header(&mut self) -> Result<(), io::Error> {
+        if let Some(header) = &self.header {
+            copy_encode(header as &[u8], &self.file, COMPRESSION_LEVEL)?;
+            self.consumed_data += header.len();
+        }
 
-        let archived_path = archive_directory.join(filename.file_name().unwrap());
+        Ok(())
+    }
+
+    fn archive(filename: &Path) -> Result<(), io::Error> {
+        let archived_path = archived_filename(filename);
+        create_dir_all(archived_path.parent().unwrap())?;
 
         info!("Closed {}", filename.display());
-        rename(&filename, &archived_path)
+        rename(filename, &archived_path)
     }
 
     fn rotate(&mut self) -> Result<(), io::Error> {
         let old_filename = generate_filename(&self.base_filename, self.index);
-        self.index += 1;
+        self.index = next_free_index(&self.base_filename, self.index + 1);
         let filename = generate_filename(&self.base_filename, self.index);
         self.file = File::create(&filename)?;
         info!("Opened {}", filename.display());
         self.consumed_data = 0;
+        self.write_header()?;
 
         LogFile::archive(&old_filename)?;
         Ok(())
@@ -73,6 +79,11 @@ impl LogFile {
         Ok(())
     }
 
+    /// Wait for the written data to reach the disk
+    pub fn sync(&self) -> Result<(), io::Error> {
+        self.file.sync_data()
+    }
+
     pub fn base_filename(&self) -> &Path {
         &self.base_filename
     }
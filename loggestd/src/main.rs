@@ -1,23 +1,16 @@
 use env_logger::{self, Env};
-#[cfg(windows)]
-use futures::{future, Future};
+use log::info;
 #[cfg(unix)]
-use log::debug;
-use log::{error, info};
+use log::{debug, error};
 #[cfg(windows)]
 use std::ffi::OsString;
 #[cfg(unix)]
 use std::fs;
-use std::sync::Arc;
 #[cfg(windows)]
 use std::time::Duration;
 use structopt::StructOpt;
 #[cfg(unix)]
-use tokio::net::unix::UnixListener;
-#[cfg(windows)]
-use tokio::net::TcpListener;
 use tokio::prelude::*;
-use tokio::runtime::Runtime;
 #[cfg(windows)]
 use windows_service::service;
 #[cfg(windows)]
@@ -27,22 +20,12 @@ use windows_service::service_control_handler;
 windows_service::define_windows_service!(service_entry_point, service_main);
 
 mod args;
-mod codec;
-mod log_file;
-mod session;
-mod usage_monitor;
 
 #[cfg(windows)]
 const SERVICE_NAME: &str = "Loggest";
 #[cfg(windows)]
 const SERVICE_TYPE: service::ServiceType = service::ServiceType::OWN_PROCESS;
 
-#[cfg(windows)]
-fn wait_for_recv(recv: crossbeam_channel::Receiver<()>) -> impl Future<Item = (), Error = ()> {
-    recv.recv().ok();
-    future::ok(())
-}
-
 enum CrossbeamReceiverOption {
     #[cfg(unix)]
     None,
@@ -51,49 +34,29 @@ enum CrossbeamReceiverOption {
 }
 
 fn run_loggest(stop_recv_option: CrossbeamReceiverOption) {
-    let opt = Arc::new(args::Opt::from_args());
+    let opt = args::Opt::from_args();
 
     env_logger::from_env(Env::default().default_filter_or("info"))
         .format_timestamp(None)
         .init();
 
     #[cfg(unix)]
-    let socket = {
+    let server = {
         if opt.unix_socket.exists() {
             debug!("Deleting {}", opt.unix_socket.display());
             fs::remove_file(&opt.unix_socket).unwrap();
         }
 
         info!("Listening in {}", opt.unix_socket.display());
-
-        UnixListener::bind(&opt.unix_socket).unwrap().incoming()
+        loggestd::Server::bind(&opt.directory, &opt.unix_socket).unwrap()
     };
 
     #[cfg(windows)]
-    let socket = {
+    let server = {
         info!("Listening in {}", opt.listen);
-        TcpListener::bind(&opt.listen).unwrap().incoming()
+        loggestd::Server::bind(&opt.directory, &opt.listen).unwrap()
     };
 
-    info!("Logging to {}", opt.directory.display());
-
-    let server = socket
-        .for_each({
-            let opt = opt.clone();
-            {
-                move |socket| {
-                    info!("Connected: {:?}", socket);
-                    tokio::spawn(session::LoggestdSession::new(socket, opt.clone()).map_err(|e| {
-                        error!("Session error: {}", e);
-                    }));
-                    Ok(())
-                }
-            }
-        })
-        .map_err(|e| {
-            error!("Error accepting: {:?}", e);
-        });
-
     #[cfg(unix)]
     let ctrl_c = tokio_signal::ctrl_c()
         .flatten_stream()
@@ -113,22 +76,19 @@ fn run_loggest(stop_recv_option: CrossbeamReceiverOption) {
         ctrl_c.select(sigterm)
     };
 
-    let mut rt = Runtime::new().unwrap();
-    rt.spawn(server);
-    rt.spawn(usage_monitor::UsageMonitor::new(&opt.directory).map_err(|e| {
-        error!("Usage monitor error: {}", e);
-    }));
-
     #[cfg(unix)]
-    rt.block_on(ctrl_c).ok();
+    ctrl_c.wait().ok();
 
     match stop_recv_option {
         #[cfg(unix)]
         CrossbeamReceiverOption::None => (),
         #[cfg(windows)]
-        CrossbeamReceiverOption::Receiver(recv) => rt.block_on(wait_for_recv(recv)).unwrap(),
+        CrossbeamReceiverOption::Receiver(recv) => {
+            recv.recv().ok();
+        }
     }
 
+    server.shutdown();
     info!("Server exited");
 }
 
@@ -1,16 +1,22 @@
-use super::args::Opt;
-use super::codec::{LoggestdCodec, LoggestdData::*};
-use super::log_file::LogFile;
+use crate::codec::{LoggestdCodec, LoggestdData::*};
+use crate::control::Listeners;
+use crate::log_file::LogFile;
+use bytes::Bytes;
 use futures::prelude::*;
+use futures::sync::mpsc::UnboundedReceiver;
 use futures::try_ready;
 use log::{info, trace};
+use loggest_protocol::Reply;
 use std::default::Default;
 use std::fmt::Debug;
 use std::io;
 use std::path::PathBuf;
 use std::sync::Arc;
 use tokio::codec::FramedRead;
-use tokio::{io::ReadHalf, prelude::*};
+use tokio::{
+    io::{ReadHalf, WriteHalf},
+    prelude::*,
+};
 
 enum State {
     Initiated,
@@ -18,41 +24,85 @@ enum State {
 }
 
 impl State {
-    fn unwrap_file(&mut self) -> &mut LogFile {
+    fn file(&mut self) -> Result<&mut LogFile, io::Error> {
         if let State::FileOpened(f) = self {
-            f
+            Ok(f)
         } else {
-            panic!("Expected file");
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Data sent before a handshake",
+            ))
         }
     }
 
-    fn open_file(&mut self, filename: PathBuf) -> Result<(), io::Error> {
+    fn open_file(&mut self, filename: PathBuf, header: Option<Bytes>) -> Result<(), io::Error> {
         if let State::FileOpened(_) = self {
-            panic!("File already opened");
-        } else {
-            *self = State::FileOpened(LogFile::open(filename)?);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "File already opened"));
         }
 
+        *self = State::FileOpened(LogFile::open(filename, header)?);
         Ok(())
     }
 }
 
 pub struct LoggestdSession<C: AsyncRead + AsyncWrite + Debug> {
     state: State,
-    opt: Arc<Opt>,
+    directory: Arc<PathBuf>,
     reader: FramedRead<ReadHalf<C>, LoggestdCodec>,
+    writer: WriteHalf<C>,
+    /// Replies not yet written to the client
+    replies: Vec<u8>,
+    listeners: Listeners,
+    /// The ID and the filter directives of a process listening for them
+    listener: Option<(u64, UnboundedReceiver<String>)>,
 }
 
 impl<C: AsyncRead + AsyncWrite + Debug> LoggestdSession<C> {
-    pub fn new(connection: C, opt: Arc<Opt>) -> Self {
-        let (r, _) = connection.split();
+    pub fn new(connection: C, directory: Arc<PathBuf>, listeners: Listeners) -> Self {
+        let (r, writer) = connection.split();
         let reader = FramedRead::new(r, LoggestdCodec::default());
         Self {
             reader,
-            opt,
+            writer,
+            replies: Vec::new(),
+            listeners,
+            listener: None,
+            directory,
             state: State::Initiated,
         }
     }
+
+    fn reply(&mut self, reply: Reply) {
+        reply.encode(&mut self.replies);
+    }
+
+    /// Queue the filter directives sent to a listening process
+    fn poll_directives(&mut self) {
+        let mut directives = Vec::new();
+        if let Some((_, receiver)) = self.listener.as_mut() {
+            while let Ok(Async::Ready(Some(d))) = receiver.poll() {
+                directives.push(d);
+            }
+        }
+
+        for d in directives {
+            self.reply(Reply::SetFilters(&d));
+        }
+    }
+
+    /// Write as many of the pending replies as possible without blocking
+    fn poll_replies(&mut self) -> Result<(), io::Error> {
+        while !self.replies.is_empty() {
+            match self.writer.poll_write(&self.replies)? {
+                Async::Ready(n) => {
+                    self.replies.drain(..n);
+                }
+                Async::NotReady => break,
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl<C: AsyncRead + AsyncWrite + Debug> Future for LoggestdSession<C> {
@@ -61,17 +111,50 @@ impl<C: AsyncRead + AsyncWrite + Debug> Future for LoggestdSession<C> {
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         loop {
+            self.poll_directives();
+            self.poll_replies()?;
             if let Some(packet) = try_ready!(self.reader.poll()) {
                 trace!("frame: {:x?}", packet);
 
                 match packet {
                     FileName(f) => {
-                        self.state.open_file(self.opt.directory.join(f))?;
+                        self.state.open_file(self.directory.join(f), None)?;
+                    }
+                    Session {
+                        filename,
+                        thread_name,
+                        header,
+                    } => {
+                        if let Some(thread_name) = thread_name {
+                            info!("Session of thread {} logs to {}", thread_name, filename.display());
+                        }
+                        self.state.open_file(self.directory.join(filename), Some(header))?;
+                    }
+                    Listener(filename) => {
+                        info!("Process logging to {} listens for filter changes", filename.display());
+                        self.listener = Some(self.listeners.register(filename));
+                    }
+                    SetFilters { filename, directives } => {
+                        let sent = self.listeners.send(&filename, &directives);
+                        info!(
+                            "Sent filter directives {:?} to {} processes logging to {}",
+                            directives,
+                            sent,
+                            filename.display()
+                        );
+                        self.reply(Reply::Sent(sent as u32));
                     }
                     FileData(data) => {
-                        let f = self.state.unwrap_file();
+                        let f = self.state.file()?;
                         f.write(&data)?;
                     }
+                    Sync { id, durable } => {
+                        // The preceding data was already written by `LogFile::write`
+                        if durable {
+                            self.state.file()?.sync()?;
+                        }
+                        self.reply(Reply::Ack(id));
+                    }
                 };
             } else {
                 return Ok(Async::Ready(()));
@@ -82,6 +165,12 @@ impl<C: AsyncRead + AsyncWrite + Debug> Future for LoggestdSession<C> {
 
 impl<C: AsyncRead + AsyncWrite + Debug> Drop for LoggestdSession<C> {
     fn drop(&mut self) {
+        if let Some((id, _)) = self.listener.take() {
+            self.listeners.unregister(id);
+            info!("Listener disconnected");
+            return;
+        }
+
         match self.state {
             State::FileOpened(ref f) => {
                 info!("Disconnected {}", f.base_filename().display());
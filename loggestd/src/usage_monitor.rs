@@ -34,7 +34,7 @@ impl SpaceData {
 
 #[cfg(unix)]
 impl From<Statvfs> for SpaceData {
-    #[allow(clippy::identity_conversion)]
+    #[allow(clippy::useless_conversion)]
     fn from(source: Statvfs) -> Self {
         Self {
             available: u64::from(source.blocks_available()) * source.fragment_size(),
@@ -45,9 +45,7 @@ impl From<Statvfs> for SpaceData {
 
 #[cfg(unix)]
 fn get_fs_data(directory: &Path) -> Result<SpaceData, io::Error> {
-    Ok(statvfs(directory)
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
-        .into())
+    Ok(statvfs(directory).map_err(io::Error::other)?.into())
 }
 
 #[cfg(windows)]
@@ -65,10 +63,7 @@ fn get_fs_data(directory: &Path) -> Result<SpaceData, io::Error> {
         fileapi::GetDiskFreeSpaceExW(wstr.as_ptr(), &mut avail as *mut _, &mut total as *mut _, null_mut())
     } == FALSE
     {
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            format!("OS Error: {}", unsafe { GetLastError() }),
-        ));
+        return Err(io::Error::other(format!("OS Error: {}", unsafe { GetLastError() })));
     }
 
     Ok(SpaceData {
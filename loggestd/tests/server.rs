@@ -0,0 +1,137 @@
+#![cfg(unix)]
+
+use log::{debug, info, log_enabled, warn, Level};
+use loggest_protocol::{read_frame, Handshake, Reply};
+use std::fs::{self, File};
+use std::io::{BufReader, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("loggestd-{}-{}", name, std::process::id()));
+    fs::remove_dir_all(&dir).ok();
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Open all the files written to `directory`, archived or not
+fn open_logs(directory: &Path) -> Vec<ioym::Ioym<BufReader<File>>> {
+    let mut files: Vec<_> = fs::read_dir(directory)
+        .unwrap()
+        .chain(fs::read_dir(directory.join("archived")).into_iter().flatten())
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().and_then(|e| e.to_str()) == Some(ioym::EXT))
+        .collect();
+    files.sort();
+    files
+        .into_iter()
+        .map(|file| ioym::Ioym::with_reader(File::open(file).unwrap()).unwrap())
+        .collect()
+}
+
+/// Send filter directives to the processes logging to `filename` like `loggestctl set-filter`, returning to how
+/// many they were sent
+fn set_filter(socket: &Path, filename: &str, directives: &str) -> u32 {
+    let mut transport = UnixStream::connect(socket).unwrap();
+    Handshake {
+        filename: filename.to_owned(),
+        set_filters: Some(directives.to_owned()),
+        ..Default::default()
+    }
+    .write(&mut transport)
+    .unwrap();
+
+    let mut reply = Vec::new();
+    read_frame(&mut transport, &mut reply).unwrap();
+    match Reply::decode(&reply).unwrap() {
+        Some(Reply::Sent(sent)) => sent,
+        reply => panic!("Unexpected reply {:?}", reply),
+    }
+}
+
+/// Wait up to a few seconds for `condition`
+fn wait_for<F: FnMut() -> bool>(mut condition: F) -> bool {
+    let start = Instant::now();
+    while !condition() {
+        if start.elapsed() > Duration::from_secs(5) {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    true
+}
+
+/// Decode all the files written to `directory`, one after the other
+fn read_logs(directory: &Path) -> String {
+    let mut output = Vec::new();
+    for mut ioym in open_logs(directory) {
+        ioym.decode(&mut output).unwrap();
+    }
+    String::from_utf8(output).unwrap()
+}
+
+#[test]
+fn test_server() {
+    let dir = temp_dir("server");
+    let socket = dir.join("loggestd.sock");
+    let server = loggestd::Server::bind(dir.join("logs"), &socket).unwrap();
+
+    let _flush = loggest::Builder::new("test")
+        .socket(socket.to_str().unwrap())
+        .remote_control(true)
+        .init()
+        .unwrap();
+    info!("hello from the main thread");
+    thread::spawn(|| {
+        let _request = loggest::context::push("req_id", "f3a9");
+        warn!("hello from another thread");
+        log::logger().flush();
+    })
+    .join()
+    .unwrap();
+    log::logger().flush();
+
+    // Once the control thread of loggest listens, the directives reach it
+    assert!(!log_enabled!(Level::Debug));
+    assert_eq!(set_filter(&socket, "other", "debug"), 0);
+    assert!(wait_for(|| set_filter(&socket, "test", "debug") == 1));
+    assert!(wait_for(|| log_enabled!(Level::Debug)));
+    debug!("hello after set-filter");
+    log::logger().flush();
+
+    // A control session sending records is disconnected, without affecting the others
+    let mut misbehaving = UnixStream::connect(&socket).unwrap();
+    Handshake {
+        filename: "test".to_owned(),
+        control: true,
+        ..Default::default()
+    }
+    .write(&mut misbehaving)
+    .unwrap();
+    misbehaving.write_all(&[4, 0, 0, 0, 1, 2, 3, 4]).unwrap();
+    assert_eq!(misbehaving.read(&mut [0; 16]).unwrap(), 0);
+    assert_eq!(set_filter(&socket, "test", "debug"), 1);
+
+    server.shutdown();
+    assert!(!socket.exists());
+
+    let logs = read_logs(&dir.join("logs"));
+    assert!(logs.contains("[INFO] server -- hello from the main thread"), "{}", logs);
+    assert!(
+        logs.contains("[WARN] server {req_id=f3a9} -- hello from another thread"),
+        "{}",
+        logs
+    );
+    assert!(logs.contains("[DEBUG] server -- hello after set-filter"), "{}", logs);
+
+    // Merged in the order they were logged, whichever file is first
+    let mut merged = Vec::new();
+    ioym::merge(open_logs(&dir.join("logs")), &mut merged).unwrap();
+    let merged = String::from_utf8(merged).unwrap();
+    let main = merged.find("hello from the main thread");
+    let other = merged.find("hello from another thread");
+    assert!(main.is_some() && main < other, "{}", merged);
+    fs::remove_dir_all(dir).ok();
+}
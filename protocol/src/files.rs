@@ -0,0 +1,30 @@
+use std::path::{Path, PathBuf};
+
+/// The name of the file of a session with the given index, `<file name>.NN.ioym`
+pub fn generate_filename(base_name: &Path, index: usize) -> PathBuf {
+    let mut path = PathBuf::from(base_name);
+
+    let new_filename = format!("{}.{:02}.ioym", path.file_name().unwrap().to_str().unwrap(), index);
+    path.set_file_name(new_filename);
+    path
+}
+
+/// Where `loggestd` moves a file once it is closed
+pub fn archived_filename(filename: &Path) -> PathBuf {
+    filename
+        .parent()
+        .unwrap()
+        .join("archived")
+        .join(filename.file_name().unwrap())
+}
+
+/// Find the first index from `start` which is neither in use nor archived, so that a reconnecting session
+/// does not overwrite the files of its previous connection.
+pub fn next_free_index(base_name: &Path, start: usize) -> usize {
+    (start..)
+        .find(|&index| {
+            let filename = generate_filename(base_name, index);
+            !filename.exists() && !archived_filename(&filename).exists()
+        })
+        .unwrap()
+}
@@ -0,0 +1,202 @@
+use crate::*;
+use std::io::{Read, Write};
+use std::time::Duration;
+
+/// The unit of the timestamps of a session
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Precision {
+    #[default]
+    Millis,
+    Micros,
+    Nanos,
+}
+
+impl Precision {
+    /// The number of digits of the fractional seconds
+    pub fn digits(self) -> u8 {
+        match self {
+            Precision::Millis => 3,
+            Precision::Micros => 6,
+            Precision::Nanos => 9,
+        }
+    }
+
+    fn from_digits(digits: u8) -> Option<Self> {
+        match digits {
+            3 => Some(Precision::Millis),
+            6 => Some(Precision::Micros),
+            9 => Some(Precision::Nanos),
+            _ => None,
+        }
+    }
+
+    /// Convert the time since the epoch to a timestamp
+    pub fn timestamp(self, since_epoch: Duration) -> u64 {
+        (match self {
+            Precision::Millis => since_epoch.as_millis(),
+            Precision::Micros => since_epoch.as_micros(),
+            Precision::Nanos => since_epoch.as_nanos(),
+        }) as u64
+    }
+
+    /// Convert a timestamp to the time since the epoch
+    pub fn since_epoch(self, timestamp: u64) -> Duration {
+        match self {
+            Precision::Millis => Duration::from_millis(timestamp),
+            Precision::Micros => Duration::from_micros(timestamp),
+            Precision::Nanos => Duration::from_nanos(timestamp),
+        }
+    }
+}
+
+/// Describes a session
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Handshake {
+    pub filename: String,
+    pub thread_name: Option<String>,
+    /// The system thread ID of the session, 0 if it is not a thread's
+    pub thread_id: u64,
+    /// Receive filter changes from loggestd
+    pub control: bool,
+    /// Send filter directives to the control sessions with the same file name
+    pub set_filters: Option<String>,
+    /// The unit of the timestamps of the records
+    pub precision: Precision,
+    /// Nanoseconds since the epoch at which the monotonic clock of the [`Sequence`] of the records started, 0 if
+    /// unknown
+    pub origin: u64,
+}
+
+impl Handshake {
+    /// Encode the handshake at the end of `buf`
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(MAGIC);
+        buf.push(if self.precision == Precision::Millis {
+            VERSION_MILLIS
+        } else {
+            VERSION
+        });
+        let start = begin_length(buf);
+
+        buf.push(HANDSHAKE_FILENAME);
+        write_str16(buf, self.filename.as_bytes());
+
+        if let Some(thread_name) = &self.thread_name {
+            buf.push(HANDSHAKE_THREAD_NAME);
+            write_str16(buf, thread_name.as_bytes());
+        }
+
+        buf.push(HANDSHAKE_THREAD_ID);
+        write_str16(buf, &self.thread_id.to_le_bytes());
+
+        if self.control {
+            buf.push(HANDSHAKE_CONTROL);
+            write_str16(buf, &[]);
+        }
+
+        if let Some(directives) = &self.set_filters {
+            buf.push(HANDSHAKE_SET_FILTERS);
+            write_str16(buf, directives.as_bytes());
+        }
+
+        // Left out in milliseconds, so that older decoders read the session
+        if self.precision != Precision::Millis {
+            buf.push(HANDSHAKE_PRECISION);
+            write_str16(buf, &[self.precision.digits()]);
+        }
+
+        if self.origin != 0 {
+            buf.push(HANDSHAKE_ORIGIN);
+            write_str16(buf, &self.origin.to_le_bytes());
+        }
+
+        end_length(buf, start);
+    }
+
+    pub fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let mut buf = Vec::new();
+        self.encode(&mut buf);
+        w.write_all(&buf)
+    }
+
+    /// Decode a handshake from the start of `buf`, returning it with its size, or `None` if `buf` does not hold
+    /// all of it yet
+    pub fn decode(buf: &[u8]) -> Result<Option<(Self, usize)>, Error> {
+        let prefix = buf.len().min(MAGIC.len());
+        if buf[..prefix] != MAGIC[..prefix] {
+            return Err(Error::InvalidHandshake);
+        }
+        if buf.len() < PREFIX_SIZE + 4 {
+            return Ok(None);
+        }
+        if !(VERSION_MILLIS..=VERSION).contains(&buf[MAGIC.len()]) {
+            return Err(Error::UnsupportedVersion(buf[MAGIC.len()]));
+        }
+
+        let mut reader = Reader {
+            buf: &buf[PREFIX_SIZE..],
+        };
+        let length = reader.u32()? as usize;
+        match reader.buf.get(..length) {
+            Some(entries) => Ok(Some((Self::parse_entries(entries)?, PREFIX_SIZE + 4 + length))),
+            None => Ok(None),
+        }
+    }
+
+    /// Read a handshake from the start of `r`
+    pub fn read<R: Read>(r: &mut R) -> Result<Self, Error> {
+        let mut buf = vec![0; PREFIX_SIZE + 4];
+        r.read_exact(&mut buf)?;
+        if buf[..MAGIC.len()] != MAGIC[..] {
+            return Err(Error::InvalidHandshake);
+        }
+        if !(VERSION_MILLIS..=VERSION).contains(&buf[MAGIC.len()]) {
+            return Err(Error::UnsupportedVersion(buf[MAGIC.len()]));
+        }
+
+        let length = Reader {
+            buf: &buf[PREFIX_SIZE..],
+        }
+        .u32()?;
+        let mut entries = Vec::new();
+        r.take(u64::from(length)).read_to_end(&mut entries)?;
+        if entries.len() < length as usize {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        Self::parse_entries(&entries)
+    }
+
+    fn parse_entries(entries: &[u8]) -> Result<Self, Error> {
+        let mut reader = Reader { buf: entries };
+        let mut filename = None;
+        let mut handshake = Self::default();
+        while !reader.buf.is_empty() {
+            let tag = reader.u8().map_err(|_| Error::InvalidHandshake)?;
+            let value = reader.str16().map_err(|_| Error::InvalidHandshake)?;
+            let text = || String::from_utf8(value.to_vec()).map_err(|_| Error::InvalidHandshake);
+
+            match tag {
+                HANDSHAKE_FILENAME => filename = Some(text()?),
+                HANDSHAKE_THREAD_NAME => handshake.thread_name = Some(String::from_utf8_lossy(value).into_owned()),
+                HANDSHAKE_THREAD_ID => {
+                    handshake.thread_id = Reader { buf: value }.u64().map_err(|_| Error::InvalidHandshake)?;
+                }
+                HANDSHAKE_CONTROL => handshake.control = true,
+                HANDSHAKE_SET_FILTERS => handshake.set_filters = Some(text()?),
+                HANDSHAKE_PRECISION => {
+                    handshake.precision = match value {
+                        [digits] => Precision::from_digits(*digits).ok_or(Error::InvalidHandshake)?,
+                        _ => return Err(Error::InvalidHandshake),
+                    };
+                }
+                HANDSHAKE_ORIGIN => {
+                    handshake.origin = Reader { buf: value }.u64().map_err(|_| Error::InvalidHandshake)?;
+                }
+                _ => (),
+            }
+        }
+
+        handshake.filename = filename.ok_or(Error::InvalidHandshake)?;
+        Ok(handshake)
+    }
+}
@@ -0,0 +1,216 @@
+//! Versions 2 and 3 of the protocol between `loggest` and `loggestd`, and the format of the files written by `loggestd`.
+//! All integers are little-endian.
+//!
+//! A session starts with a handshake:
+//!
+//! | Field   | Type         |
+//! |---------|--------------|
+//! | magic   | `b"LOGGEST"` |
+//! | version | `u8`         |
+//! | length  | `u32`        |
+//! | entries | `length` bytes of `u8` tag, `u16` length, value |
+//!
+//! The entries are the file name (tag 1, UTF-8), and optionally the thread name (tag 2, UTF-8) and the system
+//! thread ID (tag 3, `u64`). An empty control entry (tag 4) makes the session receive filter changes instead of
+//! sending records: `loggestd` sends the filter directives (UTF-8) in replies of kind 2. `loggestctl` sends them
+//! in a handshake entry (tag 5, UTF-8) to the sessions with the same file name, and receives their number (`u32`)
+//! in a reply of kind 3. The precision entry (tag 6, `u8`) is the number of digits of the fractional seconds of
+//! the timestamps of the session: 3 (milliseconds, as without it), 6 (microseconds) or 9 (nanoseconds). The
+//! origin entry (tag 7, `u64`) is the time in nanoseconds since the epoch at which the monotonic clock of the
+//! process started. Unknown entries are ignored.
+//!
+//! Handshakes with a precision other than milliseconds have version 3, so that decoders of version 2, which would
+//! read their timestamps as milliseconds, reject them. Other handshakes have version 2, so that they keep working
+//! with those decoders.
+//!
+//! Followed by records, each a `u32` length of at most [`MAX_FRAME_SIZE`] and a body starting with a `u8` kind.
+//! Log records contain:
+//!
+//! | Field      | Type                     |
+//! |------------|--------------------------|
+//! | timestamp  | `u64` milliseconds (or the precision of the session) since the epoch |
+//! | level      | `u8`, 1 (error) to 5 (trace) |
+//! | thread     | `u64` system thread ID   |
+//! | line       | `u32`, 0 if unknown      |
+//! | target     | `u16` length and UTF-8   |
+//! | module     | `u16` length and UTF-8, empty if unknown |
+//! | file       | `u16` length and UTF-8, empty if unknown |
+//! | message    | `u32` length and UTF-8   |
+//! | extensions | until the end of the record, each a `u8` tag, `u32` length and value |
+//!
+//! A structured field (extension 1) is a `u16` length and UTF-8 key, and a value: a `u8` type followed by a
+//! `u32` length and UTF-8 for strings, 8 bytes for integers and floats, or a single byte for booleans. A context
+//! entry (extension 2), pushed by the thread around the record, is a `u16` length and UTF-8 key, and the rest of
+//! the extension is the UTF-8 value. The sequence (extension 3) is a `u64` number, counting the records of the
+//! process in the order they were logged by all of its threads, and the `u64` nanoseconds of its monotonic clock
+//! since the origin. Unknown extensions are skipped.
+//!
+//! A sync record (kind 2) holds a `u64` ID and `u8` flags. It is not written to the file: `loggestd` replies
+//! with an acknowledgment holding the same ID once the records before it are written, and fsynced if the
+//! durable flag (1) is set. Replies are framed like records, and acknowledgments are of kind 1.
+//!
+//! `loggestd` writes the handshake at the start of its files, followed by the records, all compressed with
+//! zstd. The files of a session are named `<file name>.NN.ioym`, from the first index which is neither in use
+//! nor moved to the `archived` directory. A file may hold several handshakes.
+//!
+//! The first two bytes of a version 1 handshake are the length of the file name (big-endian), which is never
+//! long enough to be mistaken for the magic. Version 1 files hold lines of text, each preceded by a `u64`
+//! timestamp.
+
+use std::io;
+use thiserror::Error;
+
+mod files;
+mod handshake;
+mod record;
+mod reply;
+
+pub use files::{archived_filename, generate_filename, next_free_index};
+pub use handshake::{Handshake, Precision};
+pub use record::{LogEncoder, Record, Sequence, SyncRequest, Value};
+pub use reply::Reply;
+
+pub const MAGIC: &[u8; 7] = b"LOGGEST";
+pub const VERSION: u8 = 3;
+/// The version of handshakes in milliseconds, also read by decoders of version 2
+pub const VERSION_MILLIS: u8 = 2;
+/// The magic and the version
+pub const PREFIX_SIZE: usize = 8;
+
+pub const HANDSHAKE_FILENAME: u8 = 1;
+pub const HANDSHAKE_THREAD_NAME: u8 = 2;
+pub const HANDSHAKE_THREAD_ID: u8 = 3;
+pub const HANDSHAKE_CONTROL: u8 = 4;
+pub const HANDSHAKE_SET_FILTERS: u8 = 5;
+pub const HANDSHAKE_PRECISION: u8 = 6;
+pub const HANDSHAKE_ORIGIN: u8 = 7;
+
+pub const RECORD_LOG: u8 = 1;
+pub const RECORD_SYNC: u8 = 2;
+
+pub const SYNC_DURABLE: u8 = 1;
+
+/// The largest record or reply accepted, so that a corrupt length does not make the reader allocate gigabytes
+pub const MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+pub const REPLY_ACK: u8 = 1;
+pub const REPLY_SET_FILTERS: u8 = 2;
+pub const REPLY_SENT: u8 = 3;
+
+pub const EXTENSION_FIELD: u8 = 1;
+pub const EXTENSION_CONTEXT: u8 = 2;
+pub const EXTENSION_SEQUENCE: u8 = 3;
+
+pub const VALUE_STR: u8 = 1;
+pub const VALUE_I64: u8 = 2;
+pub const VALUE_U64: u8 = 3;
+pub const VALUE_F64: u8 = 4;
+pub const VALUE_BOOL: u8 = 5;
+
+/// Error decoding the protocol
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("I/O error: `{0}`")]
+    Io(#[from] io::Error),
+
+    #[error("Invalid handshake")]
+    InvalidHandshake,
+
+    #[error("Unsupported protocol version {0}")]
+    UnsupportedVersion(u8),
+
+    #[error("Corrupt record")]
+    Corrupt,
+}
+
+/// Read a record or a reply: a `u32` length and the body, which is read into `body`. Fails with
+/// [`io::ErrorKind::InvalidData`] if the length is above [`MAX_FRAME_SIZE`].
+pub fn read_frame<R: io::Read>(r: &mut R, body: &mut Vec<u8>) -> io::Result<()> {
+    let mut length = [0; 4];
+    r.read_exact(&mut length)?;
+    read_body(r, u32::from_le_bytes(length), body)
+}
+
+/// Read a body of `length` bytes into `body`, once the length of its frame was read
+pub fn read_body<R: io::Read>(r: &mut R, length: u32, body: &mut Vec<u8>) -> io::Result<()> {
+    let length = length as usize;
+    if length > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Frame of {} bytes is too large", length),
+        ));
+    }
+    body.resize(length, 0);
+    r.read_exact(body)
+}
+
+/// Write a `u16` length and `s`, truncated if needed without cutting a UTF-8 character
+pub(crate) fn write_str16(buf: &mut Vec<u8>, s: &[u8]) {
+    let mut length = s.len().min(u16::MAX as usize);
+    // UTF-8 continuation bytes are `0b10xxxxxx`
+    while length < s.len() && length > 0 && s[length] & 0xc0 == 0x80 {
+        length -= 1;
+    }
+    let s = &s[..length];
+    buf.extend_from_slice(&(s.len() as u16).to_le_bytes());
+    buf.extend_from_slice(s);
+}
+
+/// Reserve a `u32` length at the end of `buf`, to be filled by [`end_length`]
+pub(crate) fn begin_length(buf: &mut Vec<u8>) -> usize {
+    buf.extend_from_slice(&[0; 4]);
+    buf.len()
+}
+
+pub(crate) fn end_length(buf: &mut [u8], start: usize) {
+    let length = (buf.len() - start) as u32;
+    buf[start - 4..start].copy_from_slice(&length.to_le_bytes());
+}
+
+/// Reads the little-endian fields of a buffer
+pub(crate) struct Reader<'a> {
+    pub buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn bytes(&mut self, length: usize) -> Result<&'a [u8], Error> {
+        if self.buf.len() < length {
+            return Err(Error::Corrupt);
+        }
+        let (bytes, rest) = self.buf.split_at(length);
+        self.buf = rest;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, Error> {
+        let mut bytes = [0; 2];
+        bytes.copy_from_slice(self.bytes(2)?);
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, Error> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.bytes(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, Error> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub fn str16(&mut self) -> Result<&'a [u8], Error> {
+        let length = self.u16()? as usize;
+        self.bytes(length)
+    }
+
+    pub fn str32(&mut self) -> Result<&'a [u8], Error> {
+        let length = self.u32()? as usize;
+        self.bytes(length)
+    }
+}
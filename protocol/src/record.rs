@@ -0,0 +1,269 @@
+use crate::*;
+use std::fmt;
+
+const LEVELS: [&str; 6] = ["OFF", "ERROR", "WARN", "INFO", "DEBUG", "TRACE"];
+
+/// The value of a structured field
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value<'a> {
+    Str(&'a [u8]),
+    I64(i64),
+    U64(u64),
+    F64(f64),
+    Bool(bool),
+}
+
+impl<'a> Value<'a> {
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Value::Str(s) => {
+                buf.push(VALUE_STR);
+                buf.extend_from_slice(&(s.len() as u32).to_le_bytes());
+                buf.extend_from_slice(s);
+            }
+            Value::I64(value) => {
+                buf.push(VALUE_I64);
+                buf.extend_from_slice(&value.to_le_bytes());
+            }
+            Value::U64(value) => {
+                buf.push(VALUE_U64);
+                buf.extend_from_slice(&value.to_le_bytes());
+            }
+            Value::F64(value) => {
+                buf.push(VALUE_F64);
+                buf.extend_from_slice(&value.to_le_bytes());
+            }
+            Value::Bool(value) => {
+                buf.push(VALUE_BOOL);
+                buf.push(*value as u8);
+            }
+        }
+    }
+
+    fn decode(reader: &mut Reader<'a>) -> Result<Self, Error> {
+        Ok(match reader.u8()? {
+            VALUE_STR => Value::Str(reader.str32()?),
+            VALUE_I64 => Value::I64(reader.u64()? as i64),
+            VALUE_U64 => Value::U64(reader.u64()?),
+            VALUE_F64 => Value::F64(f64::from_bits(reader.u64()?)),
+            VALUE_BOOL => Value::Bool(reader.u8()? != 0),
+            _ => return Err(Error::Corrupt),
+        })
+    }
+}
+
+/// The order of a record among those of its process
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Sequence {
+    /// Counts the records of the process, across threads
+    pub number: u64,
+    /// Nanoseconds of the monotonic clock of the process since the origin of its sessions
+    pub monotonic: u64,
+}
+
+/// A log record. Strings are kept as bytes, since they may be cut in the middle of a character.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Record<'a> {
+    pub timestamp: u64,
+    pub level: u8,
+    pub thread_id: u64,
+    pub line: u32,
+    pub target: &'a [u8],
+    pub module: &'a [u8],
+    pub file: &'a [u8],
+    pub message: &'a [u8],
+    pub fields: Vec<(&'a [u8], Value<'a>)>,
+    /// The context entries of the thread, outermost first
+    pub context: Vec<(&'a [u8], &'a [u8])>,
+    /// Unless sent by an older client
+    pub sequence: Option<Sequence>,
+}
+
+impl<'a> Record<'a> {
+    pub fn level_name(&self) -> &'static str {
+        LEVELS.get(self.level as usize).copied().unwrap_or("?")
+    }
+
+    /// Encode the record at the end of `buf`, with its length
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        let mut encoder = LogEncoder::begin(buf, self);
+        if let Some(sequence) = &self.sequence {
+            encoder.sequence(sequence);
+        }
+        for (key, value) in &self.context {
+            encoder.context(key, value);
+        }
+        for (key, value) in &self.fields {
+            encoder.field(key, value);
+        }
+        encoder.end();
+    }
+
+    /// Decode the body of a record. Returns `None` for records other than log records.
+    pub fn decode(body: &'a [u8]) -> Result<Option<Self>, Error> {
+        let mut reader = Reader { buf: body };
+        if reader.u8()? != RECORD_LOG {
+            return Ok(None);
+        }
+
+        let mut record = Record {
+            timestamp: reader.u64()?,
+            level: reader.u8()?,
+            thread_id: reader.u64()?,
+            line: reader.u32()?,
+            target: reader.str16()?,
+            module: reader.str16()?,
+            file: reader.str16()?,
+            message: reader.str32()?,
+            fields: Vec::new(),
+            context: Vec::new(),
+            sequence: None,
+        };
+
+        while !reader.buf.is_empty() {
+            let tag = reader.u8()?;
+            let mut extension = Reader { buf: reader.str32()? };
+            // Unknown extensions are skipped
+            match tag {
+                EXTENSION_FIELD => {
+                    let key = extension.str16()?;
+                    record.fields.push((key, Value::decode(&mut extension)?));
+                }
+                EXTENSION_CONTEXT => {
+                    let key = extension.str16()?;
+                    record.context.push((key, extension.buf));
+                }
+                EXTENSION_SEQUENCE => {
+                    record.sequence = Some(Sequence {
+                        number: extension.u64()?,
+                        monotonic: extension.u64()?,
+                    });
+                }
+                _ => (),
+            }
+        }
+
+        Ok(Some(record))
+    }
+}
+
+/// Encodes a log record in parts, so that its message can be formatted in place with [`fmt::Write`] and its
+/// extensions written without collecting them first
+pub struct LogEncoder<'a> {
+    buf: &'a mut Vec<u8>,
+    start: usize,
+    /// The start of the message, until it is complete
+    message: Option<usize>,
+}
+
+impl<'a> LogEncoder<'a> {
+    /// Begin encoding `record` at the end of `buf`, without its fields. More of the message can be written until
+    /// the first extension.
+    pub fn begin(buf: &'a mut Vec<u8>, record: &Record) -> Self {
+        let start = begin_length(buf);
+        buf.push(RECORD_LOG);
+        buf.extend_from_slice(&record.timestamp.to_le_bytes());
+        buf.push(record.level);
+        buf.extend_from_slice(&record.thread_id.to_le_bytes());
+        buf.extend_from_slice(&record.line.to_le_bytes());
+        write_str16(buf, record.target);
+        write_str16(buf, record.module);
+        write_str16(buf, record.file);
+        let message = begin_length(buf);
+        buf.extend_from_slice(record.message);
+
+        Self {
+            buf,
+            start,
+            message: Some(message),
+        }
+    }
+
+    fn end_message(&mut self) {
+        if let Some(message) = self.message.take() {
+            end_length(self.buf, message);
+        }
+    }
+
+    /// Add an extension, whose value is written by `value`
+    pub fn extension<F: FnOnce(&mut Vec<u8>)>(&mut self, tag: u8, value: F) {
+        self.end_message();
+        self.buf.push(tag);
+        let start = begin_length(self.buf);
+        value(self.buf);
+        end_length(self.buf, start);
+    }
+
+    /// Add a structured field
+    pub fn field(&mut self, key: &[u8], value: &Value) {
+        self.extension(EXTENSION_FIELD, |buf| {
+            write_str16(buf, key);
+            value.encode(buf);
+        });
+    }
+
+    /// Add a context entry
+    pub fn context(&mut self, key: &[u8], value: &[u8]) {
+        self.extension(EXTENSION_CONTEXT, |buf| {
+            write_str16(buf, key);
+            buf.extend_from_slice(value);
+        });
+    }
+
+    /// Add the sequence of the record
+    pub fn sequence(&mut self, sequence: &Sequence) {
+        self.extension(EXTENSION_SEQUENCE, |buf| {
+            buf.extend_from_slice(&sequence.number.to_le_bytes());
+            buf.extend_from_slice(&sequence.monotonic.to_le_bytes());
+        });
+    }
+
+    pub fn end(mut self) {
+        self.end_message();
+        end_length(self.buf, self.start);
+    }
+}
+
+impl fmt::Write for LogEncoder<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.message.is_none() {
+            return Err(fmt::Error);
+        }
+        self.buf.extend_from_slice(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// Asks `loggestd` to acknowledge once the records before it are written
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SyncRequest {
+    pub id: u64,
+    /// Also fsync the records
+    pub durable: bool,
+}
+
+impl SyncRequest {
+    /// The size of the body
+    pub const SIZE: usize = 1 + 8 + 1;
+
+    /// Encode the request at the end of `buf`, with its length
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&(Self::SIZE as u32).to_le_bytes());
+        buf.push(RECORD_SYNC);
+        buf.extend_from_slice(&self.id.to_le_bytes());
+        buf.push(if self.durable { SYNC_DURABLE } else { 0 });
+    }
+
+    /// Decode the body of a record. Returns `None` for records other than sync records.
+    pub fn decode(body: &[u8]) -> Option<Self> {
+        let mut reader = Reader { buf: body };
+        if body.len() != Self::SIZE || reader.u8().ok()? != RECORD_SYNC {
+            return None;
+        }
+
+        Some(Self {
+            id: reader.u64().ok()?,
+            durable: reader.u8().ok()? & SYNC_DURABLE != 0,
+        })
+    }
+}
@@ -0,0 +1,46 @@
+use crate::*;
+
+/// A reply of `loggestd`, framed like records
+#[derive(Clone, Debug, PartialEq)]
+pub enum Reply<'a> {
+    /// Acknowledges the sync request with the ID
+    Ack(u64),
+    /// Filter directives for a control session
+    SetFilters(&'a str),
+    /// The number of control sessions to which filter directives were sent
+    Sent(u32),
+}
+
+impl<'a> Reply<'a> {
+    /// Encode the reply at the end of `buf`, with its length
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        let start = begin_length(buf);
+        match self {
+            Reply::Ack(id) => {
+                buf.push(REPLY_ACK);
+                buf.extend_from_slice(&id.to_le_bytes());
+            }
+            Reply::SetFilters(directives) => {
+                buf.push(REPLY_SET_FILTERS);
+                buf.extend_from_slice(directives.as_bytes());
+            }
+            Reply::Sent(count) => {
+                buf.push(REPLY_SENT);
+                buf.extend_from_slice(&count.to_le_bytes());
+            }
+        }
+        end_length(buf, start);
+    }
+
+    /// Decode the body of a reply. Returns `None` for unknown replies.
+    pub fn decode(body: &'a [u8]) -> Result<Option<Self>, Error> {
+        let mut reader = Reader { buf: body };
+        let reply = match reader.u8()? {
+            REPLY_ACK => Reply::Ack(reader.u64()?),
+            REPLY_SET_FILTERS => Reply::SetFilters(std::str::from_utf8(reader.buf).map_err(|_| Error::Corrupt)?),
+            REPLY_SENT => Reply::Sent(reader.u32()?),
+            _ => return Ok(None),
+        };
+        Ok(Some(reply))
+    }
+}
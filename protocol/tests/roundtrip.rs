@@ -0,0 +1,188 @@
+use loggest_protocol::{
+    read_frame, Error, Handshake, Precision, Record, Reply, Sequence, SyncRequest, Value, MAGIC, VERSION,
+    VERSION_MILLIS,
+};
+use proptest::prelude::*;
+
+fn handshake() -> impl Strategy<Value = Handshake> {
+    (
+        ".{0,64}",
+        proptest::option::of(".{0,64}"),
+        any::<u64>(),
+        any::<bool>(),
+        proptest::option::of(".{0,64}"),
+        prop_oneof![Just(Precision::Millis), Just(Precision::Micros), Just(Precision::Nanos)],
+        any::<u64>(),
+    )
+        .prop_map(
+            |(filename, thread_name, thread_id, control, set_filters, precision, origin)| Handshake {
+                filename,
+                thread_name,
+                thread_id,
+                control,
+                set_filters,
+                precision,
+                origin,
+            },
+        )
+}
+
+fn value() -> impl Strategy<Value = (Vec<u8>, u8, u64)> {
+    (proptest::collection::vec(any::<u8>(), 0..32), 0..5u8, any::<u64>())
+}
+
+fn to_value(bytes: &[u8], kind: u8, n: u64) -> Value<'_> {
+    match kind {
+        0 => Value::Str(bytes),
+        1 => Value::I64(n as i64),
+        2 => Value::U64(n),
+        3 => Value::F64(n as f64),
+        _ => Value::Bool(n > u64::MAX / 2),
+    }
+}
+
+#[test]
+fn test_long_filename() {
+    let handshake = Handshake {
+        filename: "é".repeat(40_000),
+        ..Default::default()
+    };
+    let mut buf = Vec::new();
+    handshake.encode(&mut buf);
+
+    // Truncated to the last whole character which fits
+    let (decoded, _) = Handshake::decode(&buf).unwrap().unwrap();
+    assert_eq!(decoded.filename, "é".repeat(32_767));
+}
+
+#[test]
+fn test_version() {
+    let version = |precision| {
+        let mut buf = Vec::new();
+        Handshake {
+            precision,
+            ..Default::default()
+        }
+        .encode(&mut buf);
+        buf[MAGIC.len()]
+    };
+    assert_eq!(version(Precision::Millis), VERSION_MILLIS);
+    // Rejected by decoders of version 2, instead of having its timestamps misread
+    assert_eq!(version(Precision::Nanos), VERSION);
+
+    let mut buf = Vec::new();
+    Handshake::default().encode(&mut buf);
+    buf[MAGIC.len()] = VERSION + 1;
+    assert!(matches!(
+        Handshake::decode(&buf),
+        Err(Error::UnsupportedVersion(version)) if version == VERSION + 1
+    ));
+}
+
+#[test]
+fn test_frame_too_large() {
+    let mut buf = u32::MAX.to_le_bytes().to_vec();
+    buf.extend_from_slice(b"garbage");
+    let mut body = Vec::new();
+    let error = read_frame(&mut &buf[..], &mut body).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    assert!(body.is_empty());
+}
+
+proptest! {
+    #[test]
+    fn test_handshake(handshake in handshake(), trailing in proptest::collection::vec(any::<u8>(), 0..16)) {
+        let mut buf = Vec::new();
+        handshake.encode(&mut buf);
+        let size = buf.len();
+        buf.extend_from_slice(&trailing);
+
+        prop_assert_eq!(Handshake::decode(&buf).unwrap(), Some((handshake.clone(), size)));
+        prop_assert_eq!(Handshake::decode(&buf[..size - 1]).unwrap(), None);
+        prop_assert_eq!(Handshake::read(&mut &buf[..]).unwrap(), handshake);
+    }
+
+    #[test]
+    fn test_record(
+        timestamp: u64,
+        level in 1..=5u8,
+        thread_id: u64,
+        line: u32,
+        strings in proptest::collection::vec(proptest::collection::vec(any::<u8>(), 0..64), 4),
+        extra: String,
+        fields in proptest::collection::vec((proptest::collection::vec(any::<u8>(), 0..16), value()), 0..4),
+        context in proptest::collection::vec(
+            (proptest::collection::vec(any::<u8>(), 0..16), proptest::collection::vec(any::<u8>(), 0..32)),
+            0..4,
+        ),
+        sequence in proptest::option::of((any::<u64>(), any::<u64>())),
+    ) {
+        let fields: Vec<_> = fields
+            .iter()
+            .map(|(key, (bytes, kind, n))| (&key[..], to_value(bytes, *kind, *n)))
+            .collect();
+        let record = Record {
+            timestamp,
+            level,
+            thread_id,
+            line,
+            target: &strings[0],
+            module: &strings[1],
+            file: &strings[2],
+            message: &strings[3],
+            fields,
+            context: context.iter().map(|(key, value)| (&key[..], &value[..])).collect(),
+            sequence: sequence.map(|(number, monotonic)| Sequence { number, monotonic }),
+        };
+
+        let mut buf = Vec::new();
+        record.encode(&mut buf);
+        let mut body = Vec::new();
+        read_frame(&mut &buf[..], &mut body).unwrap();
+        prop_assert_eq!(Record::decode(&body).unwrap(), Some(record.clone()));
+
+        // The message may be completed in place
+        let mut buf = Vec::new();
+        let mut encoder = loggest_protocol::LogEncoder::begin(&mut buf, &record);
+        std::fmt::Write::write_str(&mut encoder, &extra).unwrap();
+        encoder.end();
+        let message = [record.message, extra.as_bytes()].concat();
+        read_frame(&mut &buf[..], &mut body).unwrap();
+        prop_assert_eq!(
+            Record::decode(&body).unwrap(),
+            Some(Record { message: &message, fields: Vec::new(), context: Vec::new(), sequence: None, ..record })
+        );
+    }
+
+    #[test]
+    fn test_precision(nanos in 0..u64::MAX / 2) {
+        let since_epoch = std::time::Duration::from_nanos(nanos);
+        for precision in [Precision::Millis, Precision::Micros, Precision::Nanos] {
+            let truncated = precision.since_epoch(precision.timestamp(since_epoch));
+            prop_assert!(truncated <= since_epoch);
+            prop_assert!(since_epoch - truncated < std::time::Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_sync(id: u64, durable: bool) {
+        let sync = SyncRequest { id, durable };
+        let mut buf = Vec::new();
+        sync.encode(&mut buf);
+        let mut body = Vec::new();
+        read_frame(&mut &buf[..], &mut body).unwrap();
+        prop_assert_eq!(SyncRequest::decode(&body), Some(sync));
+        prop_assert_eq!(Record::decode(&body).unwrap(), None);
+    }
+
+    #[test]
+    fn test_reply(id: u64, directives: String, count: u32) {
+        for reply in &[Reply::Ack(id), Reply::SetFilters(&directives), Reply::Sent(count)] {
+            let mut buf = Vec::new();
+            reply.encode(&mut buf);
+            let mut body = Vec::new();
+            read_frame(&mut &buf[..], &mut body).unwrap();
+            prop_assert_eq!(Reply::decode(&body).unwrap(), Some(reply.clone()));
+        }
+    }
+}
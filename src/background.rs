@@ -0,0 +1,501 @@
+//! Background writer mode: each thread pushes its encoded records into its own queue, and a single writer thread
+//! sends them to loggestd, batching the records found in each queue into a single write.
+
+use crate::fallback::write_text;
+use crate::fork;
+use crate::ignore::Ignore;
+use crate::output::{self, Line, Text, ThreadOutput};
+use crate::protocol::{self, Handshake, Precision};
+use crate::session;
+use crate::Config;
+use crossbeam_queue::ArrayQueue;
+use log::Record;
+use std::cell::RefCell;
+use std::io;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How often threads waiting for the writer check that it is still running
+const WRITER_POLL: Duration = Duration::from_millis(100);
+
+/// What to do with a record when the queue of its thread is full
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Overflow {
+    /// Wait for the writer thread to make room, or drop the new record if the writer stopped
+    Block,
+
+    /// Drop the new record
+    DropNewest,
+
+    /// Drop the oldest record in the queue to make room
+    DropOldest,
+}
+
+struct Entry {
+    /// Milliseconds since the epoch, for the text
+    timestamp: u64,
+    encoded: Vec<u8>,
+    /// Only rendered when the fallback writes text
+    text: Option<Vec<u8>>,
+}
+
+/// The queue of a single thread
+struct Queue {
+    entries: ArrayQueue<Entry>,
+    /// Set by the thread once it will not push anymore
+    closed: AtomicBool,
+    /// Set by the writer once the queue is drained and its session closed
+    done: AtomicBool,
+}
+
+struct Producer {
+    queue: Arc<Queue>,
+    /// The configuration of the queue, which is registered again after [`Builder::reinit`](crate::Builder::reinit)
+    config: &'static Config,
+    thread_id: u64,
+    /// The unit of the timestamps of the session
+    precision: Precision,
+    /// The fork generation of the process which registered the queue
+    generation: usize,
+}
+
+impl Drop for Producer {
+    fn drop(&mut self) {
+        self.queue.closed.store(true, Ordering::Release);
+        wake();
+    }
+}
+
+thread_local! {
+    static PRODUCER: RefCell<Option<Producer>> = const { RefCell::new(None) };
+}
+
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+static SYNC: Mutex<SyncState> = Mutex::new(SyncState {
+    requested: 0,
+    durable: 0,
+    synced: 0,
+    failed: 0,
+});
+static SYNCED: Condvar = Condvar::new();
+/// Notified by the writer after each pass which drained or closed queues
+static PROGRESS: Mutex<()> = Mutex::new(());
+static PROGRESSED: Condvar = Condvar::new();
+
+/// Generations of the calls to [`sync`]
+struct SyncState {
+    requested: u64,
+    /// The last generation which asked for fsync
+    durable: u64,
+    synced: u64,
+    /// The last generation for which a sync failed
+    failed: u64,
+}
+
+/// The writer thread and the fork generation of the process which started it. A forked process starts its own
+/// writer, and leaks the one of its parent.
+static WRITER: AtomicPtr<(usize, JoinHandle<()>)> = AtomicPtr::new(ptr::null_mut());
+/// The last fork generation which started a writer
+static WRITER_STARTED: AtomicUsize = AtomicUsize::new(usize::MAX);
+/// The last fork generation which failed to start a writer, and logs synchronously instead
+static WRITER_FAILED: AtomicUsize = AtomicUsize::new(usize::MAX);
+/// A queue of a new thread with its fork generation and configuration, waiting to be picked up by the writer
+type Registered = (usize, Arc<Queue>, &'static Config, Handshake);
+
+/// Queues of new threads, waiting to be picked up by the writer
+static REGISTERED: Mutex<Vec<Registered>> = Mutex::new(Vec::new());
+
+/// Holds the locks of the writer while forking, so that the child does not inherit them locked by another thread
+pub(crate) struct ForkGuard {
+    _registered: MutexGuard<'static, Vec<Registered>>,
+    _sync: MutexGuard<'static, SyncState>,
+    _progress: MutexGuard<'static, ()>,
+}
+
+pub(crate) fn lock_for_fork() -> ForkGuard {
+    ForkGuard {
+        _registered: REGISTERED.lock().unwrap_or_else(|e| e.into_inner()),
+        _sync: SYNC.lock().unwrap_or_else(|e| e.into_inner()),
+        _progress: PROGRESS.lock().unwrap_or_else(|e| e.into_inner()),
+    }
+}
+
+/// The number of records dropped because the queue of their thread was full
+pub fn dropped_records() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// The writer of the current process, if started
+fn writer() -> Option<&'static JoinHandle<()>> {
+    let writer = unsafe { WRITER.load(Ordering::Acquire).as_ref()? };
+    if writer.0 == fork::generation() {
+        Some(&writer.1)
+    } else {
+        None
+    }
+}
+
+/// Whether the writer of the current process is started and has not stopped, e.g. by panicking
+fn writer_running() -> bool {
+    writer().is_some_and(|writer| !writer.is_finished())
+}
+
+/// Start the writer of the current process unless it is already started, returning whether it was
+fn start_writer() -> bool {
+    let generation = fork::generation();
+    if WRITER_STARTED.swap(generation, Ordering::AcqRel) != generation {
+        let spawned = thread::Builder::new().name("loggest".to_owned()).spawn(run);
+        match spawned {
+            Ok(handle) => WRITER.store(Box::into_raw(Box::new((generation, handle))), Ordering::Release),
+            Err(_) => WRITER_FAILED.store(generation, Ordering::Release),
+        }
+    }
+
+    // Another thread may be starting it
+    loop {
+        if writer().is_some() {
+            return true;
+        }
+        if WRITER_FAILED.load(Ordering::Acquire) == generation {
+            return false;
+        }
+        thread::yield_now();
+    }
+}
+
+fn wake() {
+    if let Some(writer) = writer() {
+        writer.thread().unpark();
+    }
+}
+
+/// Wait for the writer to make `condition` true, returning `false` if it stopped first
+fn wait_for_writer<F: Fn() -> bool>(condition: F) -> bool {
+    let mut progress = PROGRESS.lock().unwrap_or_else(|e| e.into_inner());
+    // Checked while locked, so that the writer cannot notify in between
+    while !condition() {
+        if !writer_running() {
+            return false;
+        }
+        wake();
+        progress = PROGRESSED
+            .wait_timeout(progress, WRITER_POLL)
+            .unwrap_or_else(|e| e.into_inner())
+            .0;
+    }
+    true
+}
+
+impl Producer {
+    /// Register the queue of the current thread, unless the writer cannot be started
+    fn register(config: &'static Config, capacity: usize) -> Option<Self> {
+        if !start_writer() {
+            return None;
+        }
+
+        let handshake = output::thread_handshake(config);
+        let queue = Arc::new(Queue {
+            entries: ArrayQueue::new(capacity.max(1)),
+            closed: AtomicBool::new(false),
+            done: AtomicBool::new(false),
+        });
+        let (thread_id, precision) = (handshake.thread_id, handshake.precision);
+        let generation = fork::generation();
+        REGISTERED
+            .lock()
+            .unwrap()
+            .push((generation, queue.clone(), config, handshake));
+
+        Some(Self {
+            queue,
+            config,
+            thread_id,
+            precision,
+            generation,
+        })
+    }
+
+    fn push(&self, entry: Entry, overflow: Overflow) {
+        match overflow {
+            Overflow::Block => {
+                let mut entry = entry;
+                while let Err(rejected) = self.queue.entries.push(entry) {
+                    entry = rejected;
+                    if !wait_for_writer(|| !self.queue.entries.is_full()) {
+                        // Nothing will make room anymore
+                        DROPPED.fetch_add(1, Ordering::Relaxed);
+                        return;
+                    }
+                }
+            }
+            Overflow::DropNewest => {
+                if self.queue.entries.push(entry).is_err() {
+                    DROPPED.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            Overflow::DropOldest => {
+                if self.queue.entries.force_push(entry).is_some() {
+                    DROPPED.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+        wake();
+    }
+}
+
+/// Queue a record for the writer. Returns `false` without logging it if the writer cannot be started, for the
+/// caller to write it synchronously.
+pub fn log(config: &'static Config, record: &Record) -> bool {
+    let (capacity, overflow) = config.background.expect("Background mode is not configured");
+    PRODUCER
+        .with(|producer| -> Result<bool, Ignore> {
+            let mut producer = producer.borrow_mut();
+            if producer
+                .as_ref()
+                .is_some_and(|producer| producer.generation != fork::generation())
+            {
+                // Inherited from the parent process, whose writer does not exist in this one
+                producer.take();
+            }
+            if producer
+                .as_ref()
+                .is_some_and(|producer| !ptr::eq(producer.config, config))
+            {
+                // Registered before `Builder::reinit`, the writer closes its session once it is drained
+                producer.take();
+            }
+            if producer.is_none() {
+                *producer = Producer::register(config, capacity);
+            }
+            let producer = match producer.as_ref() {
+                Some(producer) => producer,
+                None => return Ok(false),
+            };
+
+            let now = output::now()?;
+            let timestamp = now.as_millis() as u64;
+            let mut encoded = Vec::new();
+            protocol::write_record(
+                &mut encoded,
+                producer.precision.timestamp(now),
+                producer.thread_id,
+                record,
+                config.location,
+            );
+            let text = if config.fallback.is_text() {
+                let mut text = Vec::new();
+                write_text(&mut text, timestamp, record, config.format)?;
+                Some(text)
+            } else {
+                None
+            };
+
+            producer.push(
+                Entry {
+                    timestamp,
+                    encoded,
+                    text,
+                },
+                overflow,
+            );
+            Ok(true)
+        })
+        .unwrap_or(true)
+}
+
+/// Wait for the writer to send the records of the current thread and close its session
+pub fn flush() {
+    let queue = PRODUCER.with(|producer| {
+        producer
+            .borrow_mut()
+            .take()
+            .filter(|producer| producer.generation == fork::generation())
+            .map(|producer| producer.queue.clone())
+    });
+    if let Some(queue) = queue {
+        wait_for_writer(|| queue.done.load(Ordering::Acquire));
+    }
+}
+
+/// Whether the records of the current process go through the writer
+pub fn is_running() -> bool {
+    writer().is_some()
+}
+
+/// Wait for the writer to send the records queued by all threads, and for loggestd to write them (and fsync
+/// them if `durable`)
+pub fn sync(durable: bool) -> io::Result<()> {
+    let writer = match writer() {
+        Some(writer) => writer,
+        None => return Ok(()),
+    };
+
+    let mut state = SYNC.lock().unwrap();
+    state.requested += 1;
+    let generation = state.requested;
+    if durable {
+        state.durable = generation;
+    }
+
+    writer.thread().unpark();
+    while state.synced < generation {
+        if writer.is_finished() {
+            return Err(io::Error::other("The loggest writer thread stopped"));
+        }
+        state = SYNCED.wait_timeout(state, WRITER_POLL).unwrap().0;
+    }
+
+    if state.failed >= generation {
+        return Err(io::Error::other("Failed to deliver the records to loggestd"));
+    }
+    Ok(())
+}
+
+/// The writer thread, which sends the records of each queue with the configuration it was registered with
+fn run() {
+    let generation = fork::generation();
+    let mut outputs: Vec<(Arc<Queue>, ThreadOutput)> = Vec::new();
+    let mut batch = Vec::new();
+    let mut encoded = Vec::new();
+
+    loop {
+        // Taken first, so that everything queued before the request is sent by this pass
+        let (sync_requested, durable) = {
+            let state = SYNC.lock().unwrap();
+            (state.requested, state.durable > state.synced)
+        };
+        outputs.extend(
+            REGISTERED
+                .lock()
+                .unwrap()
+                .drain(..)
+                .filter(|(g, _, _, _)| *g == generation)
+                .map(|(_, queue, config, handshake)| (queue, ThreadOutput::new(config, handshake))),
+        );
+
+        let mut idle = true;
+        let mut i = 0;
+        while i < outputs.len() {
+            let (queue, output) = &mut outputs[i];
+            // Checked before draining, so that records pushed before closing are not left behind
+            let closed = queue.closed.load(Ordering::Acquire);
+
+            batch.clear();
+            encoded.clear();
+            // Bounded, so that a thread which keeps logging does not starve the others
+            for entry in std::iter::from_fn(|| queue.entries.pop()).take(queue.entries.capacity()) {
+                encoded.extend_from_slice(&entry.encoded);
+                batch.push(entry);
+            }
+
+            if !batch.is_empty() {
+                idle = false;
+                let lines: Vec<_> = batch
+                    .iter()
+                    .map(|entry| Line {
+                        timestamp: entry.timestamp,
+                        text: Text::Rendered(entry.text.as_deref().unwrap_or_default()),
+                        encoded: &entry.encoded,
+                    })
+                    .collect();
+                output.write_lines(&lines, &encoded).ok();
+            }
+
+            if closed {
+                idle = false;
+                let (queue, output) = outputs.swap_remove(i);
+                drop(output);
+                queue.done.store(true, Ordering::Release);
+            } else {
+                i += 1;
+            }
+        }
+
+        if sync_requested > SYNC.lock().unwrap().synced {
+            // Requested from all sessions before waiting, so that a stalled loggestd costs a single timeout
+            let pending: Vec<_> = outputs
+                .iter_mut()
+                .map(|(_, output)| output.request_sync(durable))
+                .collect();
+            let deadline = Instant::now() + session::SYNC_TIMEOUT;
+            let mut failed = false;
+            for ((_, output), pending) in outputs.iter_mut().zip(pending) {
+                failed |= pending
+                    .and_then(|pending| output.wait_sync(&pending, deadline))
+                    .is_err();
+            }
+
+            let mut state = SYNC.lock().unwrap();
+            state.synced = sync_requested;
+            if failed {
+                state.failed = sync_requested;
+            }
+            SYNCED.notify_all();
+        }
+
+        if idle {
+            thread::park_timeout(Duration::from_millis(100));
+        } else {
+            let _progress = PROGRESS.lock().unwrap_or_else(|e| e.into_inner());
+            PROGRESSED.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Builder;
+
+    fn producer(capacity: usize) -> Producer {
+        Producer {
+            queue: Arc::new(Queue {
+                entries: ArrayQueue::new(capacity),
+                closed: AtomicBool::new(false),
+                done: AtomicBool::new(false),
+            }),
+            config: Box::leak(Box::new(Builder::new("test").build().unwrap().0)),
+            thread_id: 0,
+            precision: Precision::Millis,
+            generation: fork::generation(),
+        }
+    }
+
+    fn entry(timestamp: u64) -> Entry {
+        Entry {
+            timestamp,
+            encoded: Vec::new(),
+            text: None,
+        }
+    }
+
+    fn queued(producer: &Producer) -> Vec<u64> {
+        std::iter::from_fn(|| producer.queue.entries.pop())
+            .map(|entry| entry.timestamp)
+            .collect()
+    }
+
+    #[test]
+    fn test_overflow() {
+        let dropped = dropped_records();
+
+        let newest = producer(2);
+        (0..5).for_each(|i| newest.push(entry(i), Overflow::DropNewest));
+        assert_eq!(queued(&newest), [0, 1]);
+        assert_eq!(dropped_records() - dropped, 3);
+
+        let oldest = producer(2);
+        (0..5).for_each(|i| oldest.push(entry(i), Overflow::DropOldest));
+        assert_eq!(queued(&oldest), [3, 4]);
+        assert_eq!(dropped_records() - dropped, 6);
+
+        // Without a writer to make room, blocking would never end
+        let block = producer(2);
+        (0..3).for_each(|i| block.push(entry(i), Overflow::Block));
+        assert_eq!(queued(&block), [0, 1]);
+        assert_eq!(dropped_records() - dropped, 7);
+    }
+}
@@ -0,0 +1,405 @@
+use crate::background::Overflow;
+use crate::control;
+use crate::fallback::Fallback;
+use crate::filter::{self, Filter, FILTER_ENV};
+use crate::fork;
+use crate::output;
+use crate::panic;
+use crate::tee::Tee;
+use crate::throttle::{Targets, Throttle};
+use crate::{Config, FlushGuard, LoggestError, Precision, CONFIG, LOGGER};
+use log::{set_logger, LevelFilter, Record};
+use std::env;
+use std::ffi::OsString;
+use std::io::{self, IsTerminal, Write};
+use std::ptr;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+#[cfg(unix)]
+const DEFAULT_SOCKET: &str = "/run/loggestd.sock";
+
+#[cfg(windows)]
+const DEFAULT_SOCKET: &str = "127.0.0.1:1099";
+
+/// A function formatting a single log line (without the trailing newline)
+pub type FormatFn = fn(&mut dyn Write, &Record) -> io::Result<()>;
+
+/// The default line format: `[<level>] <target> -- <message>`
+pub fn default_format(w: &mut dyn Write, record: &Record) -> io::Result<()> {
+    write!(w, "[{}] {} -- {}", record.level(), record.target(), record.args())
+}
+
+/// How the file of each thread is named
+#[derive(Clone, Copy, Debug)]
+pub enum ThreadFileNaming {
+    /// The main thread uses the base filename, other threads append `.<thread_id>`
+    ThreadId,
+
+    /// Every thread, including the main thread, appends `.<thread_id>`
+    AlwaysThreadId,
+
+    /// Like `ThreadId`, but named threads append `.<thread_name>.<thread_id>`, e.g. `my-service.worker-3.12345`.
+    /// Characters of the name other than ASCII letters, digits, `-` and `_` are replaced by `_`.
+    ThreadName,
+
+    /// Called with the base filename to name the file of the current thread
+    Custom(fn(&str) -> String),
+}
+
+/// Configures and installs `loggest`
+///
+/// # Example
+/// ```no_run
+/// use log::LevelFilter;
+/// use loggest::{Builder, Fallback};
+///
+/// let _flush = Builder::new("my-service")
+///     .level(LevelFilter::Debug)
+///     .filter_target("hyper", LevelFilter::Warn)
+///     .socket("/tmp/loggestd.sock")
+///     .fallback(Fallback::Stderr)
+///     .init()
+///     .unwrap();
+/// ```
+pub struct Builder {
+    filter: Filter,
+    filter_specs: Vec<String>,
+    base_filename: OsString,
+    socket: Option<String>,
+    thread_file_naming: ThreadFileNaming,
+    format: FormatFn,
+    fallback: Fallback,
+    connect_backoff: (Duration, Duration),
+    replay_capacity: usize,
+    location: bool,
+    precision: Precision,
+    background: Option<(usize, Overflow)>,
+    remote_control: bool,
+    capture: bool,
+    panic_hook: bool,
+    tee_level: Option<LevelFilter>,
+    tee_format: Option<FormatFn>,
+    tee_colors: Option<bool>,
+    throttle: Throttle,
+}
+
+impl Builder {
+    /// Create a builder. The `base_filename` is used as the name for the main thread.
+    pub fn new<P>(base_filename: P) -> Self
+    where
+        P: Into<OsString>,
+    {
+        Self {
+            filter: Filter::new(LevelFilter::Info),
+            filter_specs: Vec::new(),
+            base_filename: base_filename.into(),
+            socket: None,
+            thread_file_naming: ThreadFileNaming::ThreadId,
+            format: default_format,
+            fallback: Fallback::Discard,
+            connect_backoff: (Duration::from_millis(100), Duration::from_secs(10)),
+            replay_capacity: 64 * 1024,
+            location: false,
+            precision: Precision::Millis,
+            background: None,
+            remote_control: false,
+            capture: false,
+            panic_hook: false,
+            tee_level: None,
+            tee_format: None,
+            tee_colors: None,
+            throttle: Throttle {
+                rate_limits: Targets::new(),
+                samples: Targets::new(),
+            },
+        }
+    }
+
+    /// Set the maximum level to log for targets without a more specific filter. Defaults to `Info`.
+    pub fn level(mut self, level: LevelFilter) -> Self {
+        self.filter.insert(None, level);
+        self
+    }
+
+    /// Set the maximum level to log for `target` and its submodules.
+    pub fn filter_target<S>(mut self, target: S, level: LevelFilter) -> Self
+    where
+        S: Into<String>,
+    {
+        self.filter.insert(Some(target.into()), level);
+        self
+    }
+
+    /// Add `env_logger` style directives, e.g. `mycrate::net=trace,hyper=warn,info`.
+    ///
+    /// Directives in the `LOGGEST_FILTER` environment variable are applied after these. If any of them is invalid,
+    /// it is reported on the standard error and the variable is ignored.
+    pub fn parse_filters<S>(mut self, spec: S) -> Self
+    where
+        S: Into<String>,
+    {
+        self.filter_specs.push(spec.into());
+        self
+    }
+
+    /// Set the address of loggestd. Defaults to `LOGGESTD_SOCKET` or the platform default.
+    pub fn socket<S>(mut self, socket: S) -> Self
+    where
+        S: Into<String>,
+    {
+        self.socket = Some(socket.into());
+        self
+    }
+
+    /// Set how the file of each thread is named. Defaults to [`ThreadFileNaming::ThreadId`].
+    pub fn thread_file_naming(mut self, naming: ThreadFileNaming) -> Self {
+        self.thread_file_naming = naming;
+        self
+    }
+
+    /// Set the format of lines written as text, e.g. by [`Fallback::Stderr`] and [`Fallback::File`]. Defaults to
+    /// [`default_format`]. The [`context`](crate::context) entries of the thread are appended to each line as
+    /// ` {key=value}`, followed by the structured key-values with the `kv` feature.
+    ///
+    /// Records sent to loggestd are not formatted: they are encoded with their level, target and message, and
+    /// ioym renders them in its own format. Before the binary protocol, this format applied to them as well.
+    pub fn format(mut self, format: FormatFn) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Set what to do when loggestd cannot be reached. Defaults to [`Fallback::Discard`].
+    pub fn fallback(mut self, fallback: Fallback) -> Self {
+        self.fallback = fallback;
+        self
+    }
+
+    /// Set the delay between attempts to connect to loggestd, doubling from `min` up to `max` while it is
+    /// unreachable. Defaults to 100 milliseconds up to 10 seconds.
+    pub fn connect_backoff(mut self, min: Duration, max: Duration) -> Self {
+        self.connect_backoff = (min, max);
+        self
+    }
+
+    /// Set the number of bytes per thread kept when sending to loggestd fails, to be sent again after
+    /// reconnecting. Defaults to 64 KiB.
+    pub fn replay_buffer(mut self, capacity: usize) -> Self {
+        self.replay_capacity = capacity;
+        self
+    }
+
+    /// Set whether to send the file, line and module path of each record, shown by `ioym --location`. Defaults to
+    /// `false`, since they make every record larger.
+    pub fn location(mut self, location: bool) -> Self {
+        self.location = location;
+        self
+    }
+
+    /// Set the precision of the timestamps sent to loggestd. Finer timestamps require a loggestd and an ioym
+    /// which support them: older versions reject the sessions instead of misreading their timestamps. Defaults to
+    /// [`Precision::Millis`].
+    pub fn timestamp_precision(mut self, precision: Precision) -> Self {
+        self.precision = precision;
+        self
+    }
+
+    /// Send records to loggestd from a background thread, so that logging does not wait for loggestd. Each
+    /// thread queues up to `capacity` records, and `overflow` decides what happens when its queue is full.
+    ///
+    /// Records are encoded (and formatted, for text fallbacks) by the logging thread. See
+    /// [`dropped_records`](crate::dropped_records) for the number of records dropped so far. If the writer thread
+    /// cannot be started, records are sent by the logging thread as without this.
+    pub fn background(mut self, capacity: usize, overflow: Overflow) -> Self {
+        self.background = Some((capacity, overflow));
+        self
+    }
+
+    /// Let loggestd change the filters at runtime, as pushed by `loggestctl set-filter <base filename> <directives>`.
+    /// Forked processes listen under `<base filename>.<pid>`, the name of their files. Defaults to `false`.
+    pub fn remote_control(mut self, remote_control: bool) -> Self {
+        self.remote_control = remote_control;
+        self
+    }
+
+    /// Also write the records up to `level` to the standard error, e.g. to see them in the terminal during
+    /// development. The level is separate from the filters of the records sent to loggestd.
+    pub fn tee_stderr(mut self, level: LevelFilter) -> Self {
+        self.tee_level = Some(level);
+        self
+    }
+
+    /// Set the format of the lines written to the standard error by [`Builder::tee_stderr`]. Defaults to the
+    /// [`Builder::format`].
+    pub fn tee_format(mut self, format: FormatFn) -> Self {
+        self.tee_format = Some(format);
+        self
+    }
+
+    /// Set whether to color the lines written to the standard error by [`Builder::tee_stderr`] by level. Defaults
+    /// to whether the standard error is a terminal.
+    pub fn tee_colors(mut self, colors: bool) -> Self {
+        self.tee_colors = Some(colors);
+        self
+    }
+
+    /// Log panics at Error level, with their location and a backtrace if enabled by `RUST_BACKTRACE`, then
+    /// flush the sessions of all threads. The previous panic hook is called afterwards. Defaults to `false`.
+    pub fn panic_hook(mut self, panic_hook: bool) -> Self {
+        self.panic_hook = panic_hook;
+        self
+    }
+
+    /// Log at most `per_second` records per second from each callsite of targets without a more specific limit.
+    /// The next record let through after some were suppressed is preceded by a `Suppressed <count> messages`
+    /// record of the same callsite, as is flushing `loggest` for callsites which went quiet. Applies to the standard error too. Defaults to no limit.
+    pub fn rate_limit(mut self, per_second: u32) -> Self {
+        self.throttle.rate_limits.insert(None, per_second);
+        self
+    }
+
+    /// Log at most `per_second` records per second from each callsite of `target` and its submodules, see
+    /// [`Builder::rate_limit`].
+    pub fn rate_limit_target<S>(mut self, target: S, per_second: u32) -> Self
+    where
+        S: Into<String>,
+    {
+        self.throttle.rate_limits.insert(Some(target.into()), per_second);
+        self
+    }
+
+    /// Log each record of `target` and its submodules with a `probability` between 0 and 1, before rate
+    /// limiting. Records left out are not counted as suppressed. Initializing fails with
+    /// [`LoggestError::BadProbability`] for other probabilities.
+    pub fn sample_target<S>(mut self, target: S, probability: f64) -> Self
+    where
+        S: Into<String>,
+    {
+        self.throttle.samples.insert(Some(target.into()), probability);
+        self
+    }
+
+    /// Keep records in memory instead of sending them to loggestd, for [`testing`](crate::testing)
+    pub(crate) fn capture(mut self) -> Self {
+        self.capture = true;
+        self
+    }
+
+    /// Install `loggest` as the logger. Same as [`Builder::try_init`].
+    pub fn init(self) -> Result<FlushGuard, LoggestError> {
+        self.try_init()
+    }
+
+    /// Install `loggest` as the logger. Fails with [`LoggestError::AlreadyInitialized`] if `loggest` is already
+    /// installed, e.g. by another test of the same process, and with [`LoggestError::SetLoggerError`] if another
+    /// logger is.
+    pub fn try_init(self) -> Result<FlushGuard, LoggestError> {
+        let (remote_control, panic_hook) = (self.remote_control, self.panic_hook);
+        let (config, filter) = self.build()?;
+        let config = Box::into_raw(Box::new(config));
+        if CONFIG
+            .compare_exchange(ptr::null_mut(), config, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            drop(unsafe { Box::from_raw(config) });
+            return Err(LoggestError::AlreadyInitialized);
+        }
+
+        if let Err(e) = set_logger(&LOGGER) {
+            // Leaked, since `flush` or `sync` may have read it in the meantime
+            CONFIG.store(ptr::null_mut(), Ordering::Release);
+            return Err(e.into());
+        }
+        filter::install(filter);
+        fork::install();
+        if remote_control {
+            control::start();
+        }
+        if panic_hook {
+            panic::install();
+        }
+
+        Ok(FlushGuard)
+    }
+
+    /// Replace the configuration and filters of `loggest`, installing it if needed. Meant for test harnesses
+    /// which configure `loggest` differently in each test.
+    ///
+    /// The lines logged so far are flushed first. The next time each thread logs, it closes its session (or its
+    /// queue of the background writer) once the lines sent with the previous configuration are delivered, and
+    /// connects again with the new one. The replaced configuration is leaked, since those sessions may still be
+    /// using it.
+    pub fn reinit(self) -> Result<FlushGuard, LoggestError> {
+        if CONFIG.load(Ordering::Acquire).is_null() {
+            return self.try_init();
+        }
+
+        let (remote_control, panic_hook) = (self.remote_control, self.panic_hook);
+        let (config, filter) = self.build()?;
+        output::flush_all();
+        output::flush();
+        // The previous configuration is leaked, since other threads may still be using it
+        CONFIG.store(Box::into_raw(Box::new(config)), Ordering::Release);
+        filter::install(filter);
+        if remote_control {
+            control::start();
+        }
+        if panic_hook {
+            panic::install();
+        }
+
+        Ok(FlushGuard)
+    }
+
+    pub(crate) fn build(self) -> Result<(Config, Filter), LoggestError> {
+        let base_filename = self
+            .base_filename
+            .into_string()
+            .map_err(|_| LoggestError::BadFileName)?;
+        let socket = self
+            .socket
+            .unwrap_or_else(|| env::var("LOGGESTD_SOCKET").unwrap_or_else(|_| DEFAULT_SOCKET.into()));
+
+        if let Some(probability) = self.throttle.samples.values().find(|p| !(0.0..=1.0).contains(p)) {
+            return Err(LoggestError::BadProbability(probability));
+        }
+
+        let mut filter = self.filter;
+        for spec in &self.filter_specs {
+            filter.parse(spec).map_err(LoggestError::BadFilter)?;
+        }
+        // Like `env_logger`, a mistake in the environment does not keep the application from starting
+        if let Ok(spec) = env::var(FILTER_ENV) {
+            let mut with_env = filter.clone();
+            match with_env.parse(&spec) {
+                Ok(()) => filter = with_env,
+                Err(directive) => eprintln!("loggest: Ignoring {}: invalid directive `{}`", FILTER_ENV, directive),
+            }
+        }
+
+        let (format, tee_format, tee_colors) = (self.format, self.tee_format, self.tee_colors);
+        let tee = self.tee_level.map(|level| Tee {
+            level,
+            format: tee_format.unwrap_or(format),
+            colors: tee_colors.unwrap_or_else(|| io::stderr().is_terminal()),
+        });
+        let config = Config {
+            base_filename,
+            socket,
+            thread_file_naming: self.thread_file_naming,
+            format: self.format,
+            fallback: self.fallback,
+            connect_backoff: self.connect_backoff,
+            replay_capacity: self.replay_capacity,
+            location: self.location,
+            precision: self.precision,
+            background: self.background,
+            remote_control: self.remote_control,
+            capture: self.capture,
+            tee,
+            throttle: Some(self.throttle)
+                .filter(|throttle| !throttle.rate_limits.is_empty() || !throttle.samples.is_empty()),
+        };
+        Ok((config, filter))
+    }
+}
@@ -0,0 +1,99 @@
+//! Contextual entries attached to every record logged by the current thread while they are pushed, e.g. the ID of
+//! the request being handled.
+//!
+//! # Example
+//! ```no_run
+//! use log::info;
+//!
+//! let _request = loggest::context::push("req_id", "f3a9");
+//! info!("handling"); // Rendered by ioym as `[INFO] my_service {req_id=f3a9} -- handling`
+//! ```
+
+use std::cell::RefCell;
+use std::fmt::Display;
+use std::io::{self, Write};
+use std::marker::PhantomData;
+
+thread_local! {
+    static CONTEXT: RefCell<Vec<(String, String)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Removes its entry from the context of the thread when dropped, along with the entries pushed after it
+#[must_use = "the entry is removed when the guard is dropped"]
+pub struct ContextGuard {
+    /// The thread's context stack is one entry shorter without it
+    depth: usize,
+    /// The entry belongs to the thread which pushed it
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Drop for ContextGuard {
+    fn drop(&mut self) {
+        CONTEXT.with(|context| context.borrow_mut().truncate(self.depth));
+    }
+}
+
+/// Attach `key` with `value` formatted by `Display` to the records of the current thread, until the guard is
+/// dropped. Entries pushed later come after it, and may use the same key.
+pub fn push<K, V>(key: K, value: V) -> ContextGuard
+where
+    K: Into<String>,
+    V: Display,
+{
+    let depth = CONTEXT.with(|context| {
+        let mut context = context.borrow_mut();
+        context.push((key.into(), value.to_string()));
+        context.len() - 1
+    });
+    ContextGuard {
+        depth,
+        _not_send: PhantomData,
+    }
+}
+
+/// Call `f` with the context entries of the current thread, outermost first
+pub(crate) fn with<R, F: FnOnce(&[(String, String)]) -> R>(f: F) -> R {
+    CONTEXT.with(|context| f(&context.borrow()))
+}
+
+/// Write the context entries of the current thread as ` {key=value key=value}`, as ioym renders them
+pub(crate) fn write_text<W: Write>(w: &mut W) -> io::Result<()> {
+    with(|context| {
+        if context.is_empty() {
+            return Ok(());
+        }
+
+        w.write_all(b" {")?;
+        for (i, (key, value)) in context.iter().enumerate() {
+            if i > 0 {
+                w.write_all(b" ")?;
+            }
+            write!(w, "{}={}", key, value)?;
+        }
+        w.write_all(b"}")
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entries() -> Vec<(String, String)> {
+        with(<[_]>::to_vec)
+    }
+
+    #[test]
+    fn test_push() {
+        let request = push("req_id", "f3a9");
+        {
+            let _user = push("user", 7);
+            assert_eq!(
+                entries(),
+                [("req_id", "f3a9"), ("user", "7")].map(|(key, value)| (key.to_owned(), value.to_owned()))
+            );
+        }
+        assert_eq!(entries(), [("req_id".to_owned(), "f3a9".to_owned())]);
+        drop(request);
+        assert!(entries().is_empty());
+    }
+}
@@ -0,0 +1,63 @@
+//! Lets loggestd change the filters of the process, e.g. with `loggestctl set-filter`.
+
+use crate::filter;
+use crate::fork;
+use crate::output;
+use crate::protocol::{Handshake, Reply};
+use crate::session::Session;
+use crate::{config, Config};
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// The last fork generation which started the control thread. A forked process does not inherit the thread of
+/// its parent, and starts its own.
+static STARTED: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// Start the thread of the current process which receives filter changes from loggestd, unless already started
+pub fn start() {
+    let generation = fork::generation();
+    if STARTED.load(Ordering::Acquire) == generation || STARTED.swap(generation, Ordering::AcqRel) == generation {
+        return;
+    }
+
+    // The filters only change locally if it cannot be started
+    thread::Builder::new()
+        .name("loggest-control".to_owned())
+        .spawn(run)
+        .ok();
+}
+
+fn run() {
+    let mut delay = Duration::default();
+    loop {
+        // Read again on every attempt, since the configuration may be replaced by `Builder::reinit`
+        let config = config().expect("loggest is not initialized");
+        let (min, max) = config.connect_backoff;
+        listen(config, &mut delay).ok();
+        delay = delay.max(min).min(max);
+        thread::sleep(delay);
+        delay = (delay * 2).min(max);
+    }
+}
+
+/// Apply the filter changes sent by loggestd until disconnected
+fn listen(config: &Config, delay: &mut Duration) -> io::Result<()> {
+    let handshake = Handshake {
+        filename: output::process_filename(config),
+        thread_name: None,
+        control: true,
+        ..Default::default()
+    };
+    let mut session = Session::connect_with_read_timeout(&config.socket, None)?.establish(&handshake)?;
+    *delay = config.connect_backoff.0;
+
+    let mut reply = Vec::new();
+    loop {
+        session.read_reply(&mut reply)?;
+        if let Ok(Some(Reply::SetFilters(spec))) = Reply::decode(&reply) {
+            filter::set_filters(spec).ok();
+        }
+    }
+}
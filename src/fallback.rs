@@ -0,0 +1,369 @@
+use crate::output::{Line, Text};
+use crate::protocol::Handshake;
+use crate::FormatFn;
+use log::Record;
+#[cfg(feature = "ioym")]
+use loggest_protocol::{generate_filename, next_free_index};
+use std::borrow::Cow;
+use std::collections::VecDeque;
+#[cfg(feature = "ioym")]
+use std::fs::create_dir_all;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+#[cfg(feature = "ioym")]
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+/// What to do with log lines when loggestd cannot be reached
+#[derive(Clone, Debug)]
+pub enum Fallback {
+    /// Drop the lines
+    Discard,
+
+    /// Write the lines to the standard error
+    Stderr,
+
+    /// Append the lines as plain text to a file shared by all threads
+    File(PathBuf),
+
+    /// Write each thread's lines to `<thread file>.NN.ioym` in the given directory, named like the files of
+    /// loggestd and readable by `ioym`
+    #[cfg(feature = "ioym")]
+    Ioym(PathBuf),
+
+    /// Keep up to the given number of bytes per thread in memory, and send them once loggestd is reachable
+    Buffer(usize),
+}
+
+impl Fallback {
+    /// Whether the lines are written as text, formatted by the [`FormatFn`]
+    pub(crate) fn is_text(&self) -> bool {
+        matches!(self, Fallback::Stderr | Fallback::File(_))
+    }
+}
+
+/// An opened fallback of a single thread
+pub(crate) enum FallbackSink {
+    Discard,
+    Stderr(FormatFn),
+    File(File, FormatFn),
+    #[cfg(feature = "ioym")]
+    Ioym(IoymFile),
+    Buffer(LineBuffer),
+}
+
+/// The lines written by the ioym fallback are compressed together once there are enough of them
+#[cfg(feature = "ioym")]
+const IOYM_FRAME_SIZE: usize = 64 * 1024;
+
+/// A file of the ioym fallback, and the lines not compressed into it yet
+#[cfg(feature = "ioym")]
+pub(crate) struct IoymFile {
+    file: File,
+    pending: Vec<u8>,
+}
+
+#[cfg(feature = "ioym")]
+impl IoymFile {
+    fn create(directory: &Path, handshake: &Handshake) -> io::Result<Self> {
+        create_dir_all(directory)?;
+        let base_name = directory.join(&handshake.filename);
+        let path = generate_filename(&base_name, next_free_index(&base_name, 1));
+        let file = OpenOptions::new().write(true).create_new(true).open(path)?;
+
+        let mut pending = Vec::with_capacity(IOYM_FRAME_SIZE);
+        handshake.write(&mut pending)?;
+        Ok(Self { file, pending })
+    }
+
+    fn write(&mut self, encoded: &[u8]) -> io::Result<()> {
+        self.pending.extend_from_slice(encoded);
+        if self.pending.len() >= IOYM_FRAME_SIZE {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.pending.is_empty() {
+            zstd::stream::copy_encode(&self.pending[..], &self.file, 1)?;
+            self.pending.clear();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "ioym")]
+impl Drop for IoymFile {
+    fn drop(&mut self) {
+        self.flush().ok();
+    }
+}
+
+impl FallbackSink {
+    #[cfg_attr(not(feature = "ioym"), allow(unused_variables))]
+    pub fn open(fallback: &Fallback, format: FormatFn, handshake: &Handshake) -> io::Result<Self> {
+        Ok(match fallback {
+            Fallback::Discard => FallbackSink::Discard,
+            Fallback::Stderr => FallbackSink::Stderr(format),
+            Fallback::File(path) => {
+                FallbackSink::File(OpenOptions::new().create(true).append(true).open(path)?, format)
+            }
+            #[cfg(feature = "ioym")]
+            Fallback::Ioym(directory) => FallbackSink::Ioym(IoymFile::create(directory, handshake)?),
+            Fallback::Buffer(capacity) => FallbackSink::Buffer(LineBuffer::new(*capacity)),
+        })
+    }
+
+    pub fn write(&mut self, line: &Line) -> io::Result<()> {
+        match self {
+            FallbackSink::Discard => Ok(()),
+            FallbackSink::Stderr(format) => io::stderr().write_all(&render(line, *format)?),
+            FallbackSink::File(file, format) => file.write_all(&render(line, *format)?),
+            #[cfg(feature = "ioym")]
+            FallbackSink::Ioym(file) => file.write(line.encoded),
+            FallbackSink::Buffer(buffer) => {
+                buffer.push(line.encoded);
+                Ok(())
+            }
+        }
+    }
+
+    /// Write the lines which the sink holds back, e.g. before loggestd takes over
+    pub fn flush(&mut self) -> io::Result<()> {
+        match self {
+            #[cfg(feature = "ioym")]
+            FallbackSink::Ioym(file) => file.flush(),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn is_buffer(&self) -> bool {
+        matches!(self, FallbackSink::Buffer(_))
+    }
+
+    /// Take the buffered lines, if there are any
+    pub fn take_buffered(&mut self) -> VecDeque<Vec<u8>> {
+        match self {
+            FallbackSink::Buffer(buffer) => buffer.take(),
+            _ => VecDeque::new(),
+        }
+    }
+}
+
+/// Lines kept in memory up to a total size, dropping the oldest ones first
+pub(crate) struct LineBuffer {
+    lines: VecDeque<Vec<u8>>,
+    size: usize,
+    capacity: usize,
+}
+
+impl LineBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            lines: VecDeque::new(),
+            size: 0,
+            capacity,
+        }
+    }
+
+    pub fn push(&mut self, line: &[u8]) {
+        if line.len() > self.capacity {
+            return;
+        }
+
+        while self.size + line.len() > self.capacity {
+            self.size -= self.lines.pop_front().map_or(0, |l| l.len());
+        }
+
+        self.size += line.len();
+        self.lines.push_back(line.to_vec());
+    }
+
+    pub fn take(&mut self) -> VecDeque<Vec<u8>> {
+        self.size = 0;
+        std::mem::take(&mut self.lines)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+}
+
+fn render<'a>(line: &Line<'a>, format: FormatFn) -> io::Result<Cow<'a, [u8]>> {
+    match line.text {
+        Text::Record(record) => {
+            let mut text = Vec::with_capacity(line.encoded.len());
+            write_text(&mut text, line.timestamp, record, format)?;
+            Ok(Cow::Owned(text))
+        }
+        Text::Rendered(text) => Ok(Cow::Borrowed(text)),
+    }
+}
+
+/// Continuation lines of multi-line messages are indented by the width of the timestamp
+const INDENT: &[u8] = b"                        ";
+
+/// Write a record as `<UTC timestamp> <text>` and a newline
+pub(crate) fn write_text<W: Write>(
+    w: &mut W,
+    timestamp: u64,
+    record: &Record,
+    format: FormatFn,
+) -> io::Result<()> {
+    let (secs, millis) = (timestamp / 1000, timestamp % 1000);
+    let (days, secs) = (secs / 86400, secs % 86400);
+    let (year, month, day) = civil_from_days(days as i64);
+    write!(
+        w,
+        "{}-{:02}-{:02} {:02}:{:02}:{:02}.{:03} ",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        millis
+    )?;
+
+    let mut text = Vec::new();
+    format(&mut text, record)?;
+    // Trailing newlines are dropped so that a message ending with one does not leave an empty line
+    text.truncate(text.iter().rposition(|&b| b != b'\n').map_or(0, |i| i + 1));
+    crate::context::write_text(&mut text)?;
+    #[cfg(feature = "kv")]
+    crate::fields::write_text_fields(&mut text, record.key_values())?;
+
+    for (i, line) in text.split(|&b| b == b'\n').enumerate() {
+        if i > 0 {
+            w.write_all(b"\n")?;
+            w.write_all(INDENT)?;
+        }
+        w.write_all(line)?;
+    }
+    w.write_all(b"\n")
+}
+
+/// Convert days since the epoch to a (year, month, day) date.
+///
+/// See <http://howardhinnant.github.io/date_algorithms.html#civil_from_days>.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Exponential back-off between connection attempts
+pub(crate) struct Backoff {
+    min: Duration,
+    max: Duration,
+    current: Duration,
+    next_attempt: Option<Instant>,
+}
+
+impl Backoff {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        Self {
+            min,
+            max,
+            current: min,
+            next_attempt: None,
+        }
+    }
+
+    pub fn ready(&self) -> bool {
+        self.next_attempt.is_none_or(|next| Instant::now() >= next)
+    }
+
+    pub fn failed(&mut self) {
+        self.next_attempt = Some(Instant::now() + self.current);
+        self.current = (self.current * 2).min(self.max);
+    }
+
+    pub fn succeeded(&mut self) {
+        self.current = self.min;
+        self.next_attempt = None;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::write_text;
+    use log::{Level, Record};
+
+    #[test]
+    fn test_write_text() {
+        let record = Record::builder()
+            .level(Level::Info)
+            .target("test")
+            .args(format_args!("hello"))
+            .build();
+
+        let mut text = Vec::new();
+        write_text(&mut text, 1_528_377_136_413, &record, crate::default_format).unwrap();
+        assert_eq!(text, b"2018-06-07 13:12:16.413 [INFO] test -- hello\n".to_vec());
+
+        let record = Record::builder()
+            .level(Level::Info)
+            .target("test")
+            .args(format_args!("hello\nworld\n"))
+            .build();
+
+        let mut text = Vec::new();
+        write_text(&mut text, 1_528_377_136_413, &record, crate::default_format).unwrap();
+        assert_eq!(
+            text,
+            b"2018-06-07 13:12:16.413 [INFO] test -- hello\n                        world\n".to_vec()
+        );
+
+        let _request = crate::context::push("req_id", "f3a9");
+        let mut text = Vec::new();
+        write_text(&mut text, 1_528_377_136_413, &record, crate::default_format).unwrap();
+        assert_eq!(
+            text,
+            b"2018-06-07 13:12:16.413 [INFO] test -- hello\n                        world {req_id=f3a9}\n"
+                .to_vec()
+        );
+    }
+
+    #[cfg(feature = "ioym")]
+    #[test]
+    fn test_ioym_file() {
+        use super::IoymFile;
+        use crate::protocol::Handshake;
+        use std::fs;
+
+        let directory = std::env::temp_dir().join(format!("loggest-fallback-{}", std::process::id()));
+        fs::remove_dir_all(&directory).ok();
+        let handshake = Handshake {
+            filename: "test".to_owned(),
+            ..Default::default()
+        };
+
+        // Each file of the thread gets the next index, like the files of loggestd
+        for _ in 0..2 {
+            let mut file = IoymFile::create(&directory, &handshake).unwrap();
+            file.write(b"first").unwrap();
+            file.write(b"second").unwrap();
+        }
+
+        let mut header = Vec::new();
+        handshake.write(&mut header).unwrap();
+        for name in ["test.01.ioym", "test.02.ioym"] {
+            let compressed = fs::read(directory.join(name)).unwrap();
+            let contents = zstd::stream::decode_all(&compressed[..]).unwrap();
+            assert_eq!(contents, [&header[..], b"firstsecond"].concat());
+            // The lines are compressed in a single frame along with the handshake
+            assert_eq!(zstd::stream::encode_all(&contents[..], 1).unwrap(), compressed);
+        }
+        fs::remove_dir_all(&directory).ok();
+    }
+}
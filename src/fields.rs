@@ -0,0 +1,96 @@
+//! Structured key-values are sent as record extensions, each holding the key and a typed value. Values of types
+//! other than strings, integers, floats and booleans are sent as their `Display` representation.
+
+use log::kv::{self, Key, Source, Value, VisitSource, VisitValue};
+use loggest_protocol::LogEncoder;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+
+/// Converts a value to its protocol type. Strings and other types are written to `text`, leaving `value` unset.
+struct ValueConverter<'a> {
+    text: &'a mut String,
+    value: Option<loggest_protocol::Value<'static>>,
+}
+
+impl<'v> VisitValue<'v> for ValueConverter<'_> {
+    fn visit_any(&mut self, value: Value) -> Result<(), kv::Error> {
+        write!(self.text, "{}", value)?;
+        Ok(())
+    }
+
+    fn visit_str(&mut self, value: &str) -> Result<(), kv::Error> {
+        self.text.push_str(value);
+        Ok(())
+    }
+
+    fn visit_i64(&mut self, value: i64) -> Result<(), kv::Error> {
+        self.value = Some(loggest_protocol::Value::I64(value));
+        Ok(())
+    }
+
+    fn visit_u64(&mut self, value: u64) -> Result<(), kv::Error> {
+        self.value = Some(loggest_protocol::Value::U64(value));
+        Ok(())
+    }
+
+    fn visit_f64(&mut self, value: f64) -> Result<(), kv::Error> {
+        self.value = Some(loggest_protocol::Value::F64(value));
+        Ok(())
+    }
+
+    fn visit_bool(&mut self, value: bool) -> Result<(), kv::Error> {
+        self.value = Some(loggest_protocol::Value::Bool(value));
+        Ok(())
+    }
+}
+
+struct FieldWriter<'a, 'b> {
+    encoder: &'a mut LogEncoder<'b>,
+    /// Reused for values written as strings
+    text: String,
+}
+
+impl<'kvs> VisitSource<'kvs> for FieldWriter<'_, '_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        self.text.clear();
+        let mut converter = ValueConverter {
+            text: &mut self.text,
+            value: None,
+        };
+        value.visit(&mut converter)?;
+
+        let value = converter
+            .value
+            .unwrap_or(loggest_protocol::Value::Str(self.text.as_bytes()));
+        self.encoder.field(key.as_str().as_bytes(), &value);
+        Ok(())
+    }
+}
+
+/// Append the key-values of a record as extensions
+pub fn write_fields(encoder: &mut LogEncoder, source: &dyn Source) {
+    source
+        .visit(&mut FieldWriter {
+            encoder,
+            text: String::new(),
+        })
+        .ok();
+}
+
+struct TextWriter<'a, W: Write> {
+    w: &'a mut W,
+}
+
+impl<'kvs, W: Write> VisitSource<'kvs> for TextWriter<'_, W> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        write!(self.w, " {}={}", key, value)?;
+        Ok(())
+    }
+}
+
+/// Write the key-values of a record as ` key=value` text
+pub fn write_text_fields<W: Write>(w: &mut W, source: &dyn Source) -> io::Result<()> {
+    source
+        .visit(&mut TextWriter { w })
+        .map_err(|e| io::Error::other(e.to_string()))
+}
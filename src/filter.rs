@@ -0,0 +1,196 @@
+use crate::{config, LoggestError};
+use arc_swap::ArcSwapOption;
+use log::{set_max_level, Level, LevelFilter};
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// The environment variable holding filter directives, applied on top of the builder's
+pub const FILTER_ENV: &str = "LOGGEST_FILTER";
+
+/// The filter in use, replaced as a whole when changed so that checking it does not lock
+static FILTER: ArcSwapOption<Filter> = ArcSwapOption::const_empty();
+
+#[derive(Debug, Clone, PartialEq)]
+struct Directive {
+    target: Option<String>,
+    level: LevelFilter,
+}
+
+impl Directive {
+    fn matches(&self, target: &str) -> bool {
+        self.target.as_ref().is_none_or(|prefix| target_matches(prefix, target))
+    }
+}
+
+/// Whether `target` is `prefix` or one of its submodules
+pub(crate) fn target_matches(prefix: &str, target: &str) -> bool {
+    target.starts_with(prefix) && (target.len() == prefix.len() || target[prefix.len()..].starts_with("::"))
+}
+
+/// Per-target level filter, in the spirit of `env_logger` directives (`mycrate::net=trace,hyper=warn,info`)
+#[derive(Debug, Clone)]
+pub(crate) struct Filter {
+    /// Sorted from the most specific target to the least, so the first match wins
+    directives: Vec<Directive>,
+}
+
+impl Filter {
+    pub fn new(level: LevelFilter) -> Self {
+        Self {
+            directives: vec![Directive { target: None, level }],
+        }
+    }
+
+    /// Set the level of `target`, or the default level if `target` is `None`
+    pub fn insert(&mut self, target: Option<String>, level: LevelFilter) {
+        if let Some(directive) = self.directives.iter_mut().find(|d| d.target == target) {
+            directive.level = level;
+        } else {
+            self.directives.push(Directive { target, level });
+            self.directives
+                .sort_by_key(|d| std::cmp::Reverse(d.target.as_ref().map_or(0, |t| t.len() + 1)));
+        }
+    }
+
+    /// Parse a comma separated list of `target=level`, `target` or `level` directives
+    pub fn parse(&mut self, spec: &str) -> Result<(), String> {
+        for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            let mut parts = directive.splitn(2, '=');
+            let (target, level) = match (parts.next().unwrap().trim(), parts.next().map(str::trim)) {
+                (target, Some(level)) => (
+                    Some(target.to_owned()),
+                    LevelFilter::from_str(level).map_err(|_| directive.to_owned())?,
+                ),
+                (level_or_target, None) => match LevelFilter::from_str(level_or_target) {
+                    Ok(level) => (None, level),
+                    Err(_) => (Some(level_or_target.to_owned()), LevelFilter::Trace),
+                },
+            };
+
+            if target.as_ref().is_some_and(|t| t.is_empty()) {
+                return Err(directive.to_owned());
+            }
+            self.insert(target, level);
+        }
+
+        Ok(())
+    }
+
+    pub fn enabled(&self, target: &str, level: Level) -> bool {
+        self.directives
+            .iter()
+            .find(|d| d.matches(target))
+            .is_some_and(|d| level <= d.level)
+    }
+
+    /// The most verbose level any target may log at
+    pub fn max_level(&self) -> LevelFilter {
+        self.directives
+            .iter()
+            .map(|d| d.level)
+            .max()
+            .unwrap_or(LevelFilter::Off)
+    }
+}
+
+/// The maximum level enabled by `filter` or by the tee to the standard error
+fn max_level(filter: &Filter) -> LevelFilter {
+    let tee = config()
+        .and_then(|config| config.tee.as_ref())
+        .map_or(LevelFilter::Off, |tee| tee.level);
+    filter.max_level().max(tee)
+}
+
+/// Start filtering with `filter`
+pub(crate) fn install(filter: Filter) {
+    set_max_level(max_level(&filter));
+    FILTER.store(Some(Arc::new(filter)));
+}
+
+pub(crate) fn enabled(target: &str, level: Level) -> bool {
+    FILTER
+        .load()
+        .as_ref()
+        .is_some_and(|filter| filter.enabled(target, level))
+}
+
+fn update<F: Fn(&mut Filter)>(f: F) {
+    FILTER.rcu(|filter| {
+        filter.as_ref().map(|filter| {
+            let mut filter = Filter::clone(filter);
+            f(&mut filter);
+            Arc::new(filter)
+        })
+    });
+
+    if let Some(filter) = FILTER.load().as_ref() {
+        set_max_level(max_level(filter));
+    }
+}
+
+/// Change the maximum level to log for targets without a more specific filter. Has no effect before `loggest` is
+/// initialized.
+pub fn set_level(level: LevelFilter) {
+    update(|filter| filter.insert(None, level));
+}
+
+/// Change the maximum level to log for `target` and its submodules. Has no effect before `loggest` is
+/// initialized.
+pub fn set_target_level(target: &str, level: LevelFilter) {
+    update(|filter| filter.insert(Some(target.to_owned()), level));
+}
+
+/// Apply filter directives such as `mycrate::net=trace,hyper=warn,info` on top of the current filters. Has no
+/// effect before `loggest` is initialized.
+pub fn set_filters(spec: &str) -> Result<(), LoggestError> {
+    // Directives are only invalid by themselves, so they are checked once before updating
+    Filter::new(LevelFilter::Off)
+        .parse(spec)
+        .map_err(LoggestError::BadFilter)?;
+    update(|filter| {
+        filter.parse(spec).ok();
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::Filter;
+    use log::{Level, LevelFilter};
+
+    #[test]
+    fn test_directives() {
+        let mut filter = Filter::new(LevelFilter::Error);
+        filter.parse("mycrate::net=trace, hyper=warn,info,noisy").unwrap();
+
+        assert!(filter.enabled("mycrate::net", Level::Trace));
+        assert!(filter.enabled("mycrate::net::tcp", Level::Trace));
+        assert!(!filter.enabled("mycrate::network", Level::Debug));
+        assert!(filter.enabled("mycrate", Level::Info));
+        assert!(!filter.enabled("hyper::client", Level::Info));
+        assert!(filter.enabled("hyper::client", Level::Warn));
+        assert!(filter.enabled("noisy", Level::Trace));
+        assert_eq!(filter.max_level(), LevelFilter::Trace);
+    }
+
+    #[test]
+    fn test_runtime_changes() {
+        super::install(Filter::new(LevelFilter::Info));
+        super::set_target_level("hyper", LevelFilter::Warn);
+        assert!(super::enabled("mycrate", Level::Info));
+        assert!(!super::enabled("hyper::client", Level::Info));
+
+        super::set_level(LevelFilter::Debug);
+        assert!(super::enabled("mycrate", Level::Debug));
+        assert!(super::set_filters("hyper=loud").is_err());
+        super::set_filters("hyper=trace").unwrap();
+        assert!(super::enabled("hyper::client", Level::Trace));
+    }
+
+    #[test]
+    fn test_invalid_directive() {
+        let mut filter = Filter::new(LevelFilter::Info);
+        assert_eq!(filter.parse("hyper=loud"), Err("hyper=loud".to_owned()));
+        assert_eq!(filter.parse("=warn"), Err("=warn".to_owned()));
+    }
+}
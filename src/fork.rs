@@ -0,0 +1,51 @@
+//! Detects `fork()`, after which the child process must not use the sessions inherited from its parent.
+
+#[cfg(unix)]
+use crate::{background, output, throttle};
+#[cfg(unix)]
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The number of forks between the process which called `init` and this one
+static GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(unix)]
+thread_local! {
+    /// The locks taken before forking by the thread calling `fork()`, and released after it in both processes.
+    /// Otherwise the child would inherit the locks held by other threads, which do not exist in it to release them.
+    static HELD: RefCell<Option<(throttle::ForkGuard, output::ForkGuard, background::ForkGuard)>> =
+        const { RefCell::new(None) };
+}
+
+#[cfg(unix)]
+extern "C" fn prepare() {
+    let held = (
+        throttle::lock_for_fork(),
+        output::lock_for_fork(),
+        background::lock_for_fork(),
+    );
+    HELD.with(|locks| *locks.borrow_mut() = Some(held));
+}
+
+#[cfg(unix)]
+extern "C" fn parent() {
+    HELD.with(|locks| locks.borrow_mut().take());
+}
+
+#[cfg(unix)]
+extern "C" fn child() {
+    GENERATION.fetch_add(1, Ordering::Relaxed);
+    output::reset_sequence();
+    HELD.with(|locks| locks.borrow_mut().take());
+}
+
+pub fn install() {
+    #[cfg(unix)]
+    unsafe {
+        nix::libc::pthread_atfork(Some(prepare), Some(parent), Some(child));
+    }
+}
+
+pub fn generation() -> usize {
+    GENERATION.load(Ordering::Relaxed)
+}
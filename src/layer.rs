@@ -0,0 +1,215 @@
+//! A [`tracing_subscriber::Layer`] sending `tracing` events through the same sessions as `log` records.
+
+use crate::LOGGER;
+use log::{Level, Log, Metadata, Record};
+use std::fmt::{self, Write as _};
+use tracing_core::field::{Field, Visit};
+use tracing_core::span::{Attributes, Id, Record as SpanValues};
+use tracing_core::{Event, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+/// Sends `tracing` events through the same sessions as `log` records, so that services using either end up in
+/// the same files.
+///
+/// Events are logged like records of their target, and pass through the filters and the tee of `loggest`. The
+/// spans of an event prefix its message as `outer{key=value}:inner: `. The fields of the event are sent as
+/// structured key-values with the `kv` feature, or appended to the message as ` key=value` otherwise.
+///
+/// # Example
+/// ```no_run
+/// use tracing_subscriber::layer::SubscriberExt;
+///
+/// let _flush = loggest::init(log::LevelFilter::Info, "my-service").unwrap();
+/// let subscriber = tracing_subscriber::registry().with(loggest::LoggestLayer::new());
+/// tracing::subscriber::set_global_default(subscriber).unwrap();
+///
+/// let span = tracing::info_span!("request", id = 7);
+/// let _entered = span.enter();
+/// tracing::info!(status = 200, "handled");
+/// ```
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LoggestLayer {
+    _private: (),
+}
+
+impl LoggestLayer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+/// The fields of a span formatted as `key=value` pairs, kept in its extensions
+struct SpanFields(String);
+
+/// Writes the fields of a span as `key=value` pairs separated by spaces
+struct SpanVisitor<'a>(&'a mut String);
+
+impl Visit for SpanVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record_debug(field, &format_args!("{}", value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if !self.0.is_empty() {
+            self.0.push(' ');
+        }
+        write!(self.0, "{}={:?}", field.name(), value).ok();
+    }
+}
+
+/// The value of a field of an event, keeping the types which the protocol supports
+enum FieldValue {
+    Str(String),
+    I64(i64),
+    U64(u64),
+    F64(f64),
+    Bool(bool),
+}
+
+impl fmt::Display for FieldValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FieldValue::Str(value) => value.fmt(f),
+            FieldValue::I64(value) => value.fmt(f),
+            FieldValue::U64(value) => value.fmt(f),
+            FieldValue::F64(value) => value.fmt(f),
+            FieldValue::Bool(value) => value.fmt(f),
+        }
+    }
+}
+
+#[cfg(feature = "kv")]
+impl log::kv::ToValue for FieldValue {
+    fn to_value(&self) -> log::kv::Value<'_> {
+        match self {
+            FieldValue::Str(value) => value.to_value(),
+            FieldValue::I64(value) => value.to_value(),
+            FieldValue::U64(value) => value.to_value(),
+            FieldValue::F64(value) => value.to_value(),
+            FieldValue::Bool(value) => value.to_value(),
+        }
+    }
+}
+
+/// Collects the message and the other fields of an event
+#[derive(Default)]
+struct EventVisitor {
+    message: String,
+    fields: Vec<(&'static str, FieldValue)>,
+}
+
+impl EventVisitor {
+    fn push(&mut self, field: &Field, value: FieldValue) {
+        self.fields.push((field.name(), value));
+    }
+}
+
+impl Visit for EventVisitor {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.push(field, FieldValue::I64(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.push(field, FieldValue::U64(value));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.push(field, FieldValue::F64(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.push(field, FieldValue::Bool(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message.push_str(value);
+        } else {
+            self.push(field, FieldValue::Str(value.to_owned()));
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        // The message of the macros is `format_args!`, whose `Debug` is its `Display`
+        if field.name() == "message" {
+            write!(self.message, "{:?}", value).ok();
+        } else {
+            self.push(field, FieldValue::Str(format!("{:?}", value)));
+        }
+    }
+}
+
+fn to_level(level: &tracing_core::Level) -> Level {
+    match *level {
+        tracing_core::Level::ERROR => Level::Error,
+        tracing_core::Level::WARN => Level::Warn,
+        tracing_core::Level::INFO => Level::Info,
+        tracing_core::Level::DEBUG => Level::Debug,
+        tracing_core::Level::TRACE => Level::Trace,
+    }
+}
+
+impl<S> Layer<S> for LoggestLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes, id: &Id, ctx: Context<S>) {
+        if let Some(span) = ctx.span(id) {
+            let mut fields = String::new();
+            attrs.record(&mut SpanVisitor(&mut fields));
+            span.extensions_mut().insert(SpanFields(fields));
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &SpanValues, ctx: Context<S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(fields) = span.extensions_mut().get_mut::<SpanFields>() {
+                values.record(&mut SpanVisitor(&mut fields.0));
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event, ctx: Context<S>) {
+        let metadata = event.metadata();
+        let level = to_level(metadata.level());
+        if !LOGGER.enabled(&Metadata::builder().level(level).target(metadata.target()).build()) {
+            return;
+        }
+
+        let mut text = String::new();
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                text.push_str(span.name());
+                if let Some(SpanFields(fields)) = span.extensions().get::<SpanFields>() {
+                    if !fields.is_empty() {
+                        write!(text, "{{{}}}", fields).ok();
+                    }
+                }
+                text.push(':');
+            }
+            if !text.is_empty() {
+                text.push(' ');
+            }
+        }
+
+        let mut visitor = EventVisitor::default();
+        event.record(&mut visitor);
+        text.push_str(&visitor.message);
+        #[cfg(not(feature = "kv"))]
+        for (key, value) in &visitor.fields {
+            write!(text, " {}={}", key, value).ok();
+        }
+
+        let mut builder = Record::builder();
+        builder
+            .level(level)
+            .target(metadata.target())
+            .module_path_static(metadata.module_path())
+            .file_static(metadata.file())
+            .line(metadata.line());
+        #[cfg(feature = "kv")]
+        builder.key_values(&visitor.fields);
+        LOGGER.log(&builder.args(format_args!("{}", text)).build());
+    }
+}
@@ -5,28 +5,89 @@
 
 //! # Multithreading
 //!
-//! Each thread maintains its connection to the log daemon to avoid locking for each log line.
+//! Each thread maintains its connection to the log daemon to avoid contention on each log line.
+//! Alternatively, [`Builder::background`] has threads queue their lines for a single writer thread, so that
+//! a slow log daemon does not hold them up.
 
+//! # Forking
+//!
+//! A process forked after initialization does not use the connections of its parent. Its threads connect
+//! again, with `.<pid>` appended to the base filename.
+
+//! # Tracing
+//!
+//! With the `tracing` feature, `LoggestLayer` sends `tracing` events through the same sessions as `log`
+//! records.
+
+mod background;
+mod builder;
+pub mod context;
+mod control;
+mod fallback;
+#[cfg(feature = "kv")]
+mod fields;
+mod filter;
+mod fork;
 mod ignore;
+#[cfg(feature = "tracing")]
+mod layer;
 mod output;
+mod panic;
+mod protocol;
 mod session;
+mod tee;
+pub mod testing;
+mod throttle;
 
 use derive_more::From;
-use log::{set_logger, set_max_level, LevelFilter, Log, Metadata, Record};
+use log::{LevelFilter, Log, Metadata, Record};
 use std::ffi::OsString;
 use std::io;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::time::Duration;
 use thiserror::Error;
 
-pub use output::flush;
+pub use background::{dropped_records, Overflow};
+pub use builder::{default_format, Builder, FormatFn, ThreadFileNaming};
+pub use fallback::Fallback;
+pub use filter::{set_filters, set_level, set_target_level};
+#[cfg(feature = "tracing")]
+pub use layer::LoggestLayer;
+pub use loggest_protocol::Precision;
+pub use output::{flush, sync};
 
 static LOGGER: Loggest = Loggest;
-static mut CONFIG: Option<Config> = None;
+/// The configuration, set by [`Builder::try_init`]. Configurations replaced by [`Builder::reinit`] are leaked,
+/// since the sessions made with them may still be using them.
+static CONFIG: AtomicPtr<Config> = AtomicPtr::new(ptr::null_mut());
 
 struct Loggest;
 
 struct Config {
-    level: LevelFilter,
-    base_filename: OsString,
+    base_filename: String,
+    socket: String,
+    thread_file_naming: ThreadFileNaming,
+    format: FormatFn,
+    fallback: Fallback,
+    connect_backoff: (Duration, Duration),
+    replay_capacity: usize,
+    location: bool,
+    precision: Precision,
+    background: Option<(usize, Overflow)>,
+    /// Receive filter changes from loggestd, see [`Builder::remote_control`]
+    remote_control: bool,
+    /// Keep records in memory instead of sending them, see [`testing`]
+    capture: bool,
+    tee: Option<tee::Tee>,
+    /// Rate limits and sampling, unless neither is configured
+    throttle: Option<throttle::Throttle>,
+}
+
+/// The configuration, unless `loggest` is not initialized yet
+fn config() -> Option<&'static Config> {
+    // Only ever set to leaked boxes
+    unsafe { CONFIG.load(Ordering::Acquire).as_ref() }
 }
 
 /// Error initializing `loggest`
@@ -40,11 +101,23 @@ pub enum LoggestError {
 
     #[error("File name must be a valid utf-8")]
     BadFileName,
+
+    #[error("Invalid filter directive: `{0}`")]
+    #[from(ignore)]
+    BadFilter(String),
+
+    #[error("Sampling probability must be between 0 and 1: `{0}`")]
+    #[from(ignore)]
+    BadProbability(f64),
+
+    #[error("loggest is already initialized")]
+    AlreadyInitialized,
 }
 
-/// Initialize `loggest`. Must only be called once.
+/// Initialize `loggest`. Fails with [`LoggestError::AlreadyInitialized`] if called again.
 ///
 /// The `base_filename` argument is used as the name for the main thread. Other threads append `.<thread_id>`.
+/// Use [`Builder`] for further configuration.
 ///
 /// # Example
 /// ```no_run
@@ -54,38 +127,87 @@ pub fn init<P>(level: LevelFilter, base_filename: P) -> Result<FlushGuard, Logge
 where
     P: Into<OsString>,
 {
-    let base_filename: OsString = base_filename.into();
-
-    set_logger(&LOGGER)?;
-    set_max_level(level);
-    unsafe {
-        debug_assert!(CONFIG.is_none());
-        CONFIG = Some(Config { level, base_filename });
-    }
-
-    Ok(FlushGuard)
+    Builder::new(base_filename).level(level).init()
 }
 
 impl Log for Loggest {
     fn enabled(&self, metadata: &Metadata) -> bool {
-        metadata.level() <= unsafe { CONFIG.as_ref().unwrap().level }
+        filter::enabled(metadata.target(), metadata.level())
+            || config()
+                .and_then(|config| config.tee.as_ref())
+                .is_some_and(|tee| tee.enabled(metadata.level()))
     }
 
     fn log(&self, record: &Record) {
-        if !self.enabled(record.metadata()) {
-            return;
+        if let Some(throttle) = config().and_then(|config| config.throttle.as_ref()) {
+            // Records which are filtered out do not count
+            if !self.enabled(record.metadata()) {
+                return;
+            }
+            match throttle.admit(record) {
+                throttle::Admit::Drop => return,
+                throttle::Admit::Log { suppressed: 0 } => (),
+                throttle::Admit::Log { suppressed } => self.write_suppressed(record, suppressed),
+            }
         }
 
-        output::log(record);
+        self.write(record);
     }
 
-    fn flush(&self) {}
+    /// Wait for loggestd to write the lines of all threads
+    fn flush(&self) {
+        self.flush_suppressed();
+        output::flush_all();
+    }
+}
+
+impl Loggest {
+    /// Log the number of records suppressed by rate limiting, as a record of their callsite
+    fn write_suppressed(&self, callsite: &Record, suppressed: u64) {
+        self.write(
+            &Record::builder()
+                .metadata(callsite.metadata().clone())
+                .module_path(callsite.module_path())
+                .file(callsite.file())
+                .line(callsite.line())
+                .args(format_args!("Suppressed {} messages", suppressed))
+                .build(),
+        );
+    }
+
+    /// Log the records suppressed by the callsites which did not log since
+    fn flush_suppressed(&self) {
+        for (callsite, suppressed) in throttle::take_suppressed() {
+            let record = Record::builder()
+                .level(callsite.level)
+                .target(&callsite.target)
+                .module_path(callsite.module_path.as_deref())
+                .file(callsite.file.as_deref())
+                .line(callsite.line)
+                .build();
+            self.write_suppressed(&record, suppressed);
+        }
+    }
+
+    fn write(&self, record: &Record) {
+        // The tee has its own level, and does not affect what is sent to loggestd
+        if let Some(tee) = config().and_then(|config| config.tee.as_ref()) {
+            tee.log(record);
+        }
+
+        if filter::enabled(record.target(), record.level()) {
+            output::log(record);
+        }
+    }
 }
 
+/// Waits for loggestd to write the lines of all threads, then closes the session of the current thread
 pub struct FlushGuard;
 
 impl Drop for FlushGuard {
     fn drop(&mut self) {
+        LOGGER.flush_suppressed();
+        output::flush_all();
         flush();
     }
 }
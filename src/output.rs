@@ -1,15 +1,24 @@
+use crate::background;
+use crate::control;
+use crate::fallback::{Backoff, FallbackSink, LineBuffer};
+use crate::fork;
 use crate::ignore::Ignore;
+use crate::protocol;
 use crate::session;
-use crate::CONFIG;
+use crate::testing;
+use crate::{config, Config, ThreadFileNaming};
 use log::Record;
-use std::cell::RefCell;
-use std::ffi::{OsStr, OsString};
-use std::io::Write;
+use loggest_protocol::Sequence;
+use std::cell::{Cell, RefCell};
+use std::io::{self, Write};
 #[cfg(windows)]
 use std::net::TcpStream;
 #[cfg(unix)]
 use std::os::unix::net::UnixStream;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::time::{Duration, Instant, SystemTime, SystemTimeError, UNIX_EPOCH};
 #[cfg(windows)]
 use winapi::um::processthreadsapi::GetCurrentThreadId;
 
@@ -19,8 +28,256 @@ type SessionTransport = TcpStream;
 #[cfg(unix)]
 type SessionTransport = UnixStream;
 
+pub(crate) type PendingSync = session::PendingSync<SessionTransport>;
+
 thread_local! {
-    static OUTPUT: RefCell<Option<session::EstablishedSession<SessionTransport>>> = RefCell::new(None);
+    static OUTPUT: RefCell<Option<SharedOutput>> = const { RefCell::new(None) };
+    /// Set while the thread is logging, so that records logged meanwhile (e.g. by the `Display` implementation
+    /// of an argument, or by the panic hook) are dropped instead of finding its output borrowed
+    static LOGGING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Clears [`LOGGING`] when dropped
+struct LoggingGuard;
+
+impl Drop for LoggingGuard {
+    fn drop(&mut self) {
+        LOGGING.with(|logging| logging.set(false));
+    }
+}
+
+/// Whether the current thread is in the middle of logging a record
+pub(crate) fn is_logging() -> bool {
+    LOGGING.with(Cell::get)
+}
+
+/// Counts the records of the process across threads, so that their files can be merged in order
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+/// The start of the monotonic clock of the records, and the nanoseconds since the epoch at that time. Only ever
+/// set to leaked boxes, and reset by a forked process.
+static ORIGIN: AtomicPtr<(Instant, u64)> = AtomicPtr::new(ptr::null_mut());
+
+/// The outputs of all threads with the fork generation which created them, so that they can be flushed by any
+/// thread. Each output is only locked by another thread while flushing.
+static OUTPUTS: Mutex<Vec<(usize, Weak<Mutex<ThreadOutput>>)>> = Mutex::new(Vec::new());
+
+/// Holds [`OUTPUTS`] while forking, so that the child does not inherit it locked by another thread
+pub(crate) struct ForkGuard {
+    _outputs: MutexGuard<'static, Vec<(usize, Weak<Mutex<ThreadOutput>>)>>,
+}
+
+pub(crate) fn lock_for_fork() -> ForkGuard {
+    ForkGuard {
+        _outputs: OUTPUTS.lock().unwrap_or_else(|e| e.into_inner()),
+    }
+}
+
+/// The output of a thread, shared with [`OUTPUTS`]
+struct SharedOutput {
+    generation: usize,
+    config: &'static Config,
+    output: Arc<Mutex<ThreadOutput>>,
+}
+
+/// The text of a record, for fallbacks writing text
+pub(crate) enum Text<'a> {
+    Record(&'a Record<'a>),
+    /// Already written by `write_text`, for records sent from the background writer
+    Rendered(&'a [u8]),
+}
+
+/// A record to be sent to loggestd
+pub(crate) struct Line<'a> {
+    /// Milliseconds since the epoch, for the text
+    pub timestamp: u64,
+    pub text: Text<'a>,
+    /// The record encoded in the protocol
+    pub encoded: &'a [u8],
+}
+
+/// The connection of a single thread to loggestd, and its fallback while loggestd is unreachable
+pub(crate) struct ThreadOutput {
+    /// The configuration the output was made with, which it keeps after [`Builder::reinit`](crate::Builder::reinit)
+    /// so that the lines logged before are delivered where they were meant to go
+    config: &'static Config,
+    handshake: protocol::Handshake,
+    /// Reused for encoding records
+    buffer: Vec<u8>,
+    session: Option<session::EstablishedSession<SessionTransport>>,
+    /// Lines which failed to send since loggestd went away, sent again once reconnected
+    replay: LineBuffer,
+    broken: bool,
+    fallback: Option<FallbackSink>,
+    backoff: Backoff,
+}
+
+impl ThreadOutput {
+    pub fn new(config: &'static Config, handshake: protocol::Handshake) -> Self {
+        let (min, max) = config.connect_backoff;
+        Self {
+            config,
+            handshake,
+            buffer: Vec::new(),
+            session: None,
+            replay: LineBuffer::new(config.replay_capacity),
+            broken: false,
+            fallback: None,
+            backoff: Backoff::new(min, max),
+        }
+    }
+
+    /// Connect to loggestd unless already connected, and send the lines kept in the meantime
+    fn connect(&mut self) -> io::Result<()> {
+        if self.session.is_some() {
+            return Ok(());
+        }
+
+        let mut session = session::Session::connect(&self.config.socket)?.establish(&self.handshake)?;
+
+        let mut pending = self.replay.take();
+        if let Some(fallback) = self.fallback.as_mut() {
+            fallback.flush().ok();
+            pending.extend(fallback.take_buffered());
+        }
+        while let Some(line) = pending.pop_front() {
+            if let Err(e) = session.write_all(&line) {
+                self.replay.push(&line);
+                pending.iter().for_each(|line| self.replay.push(line));
+                return Err(e);
+            }
+        }
+
+        self.session = Some(session);
+        self.broken = false;
+        Ok(())
+    }
+
+    fn try_connect(&mut self) {
+        match self.connect() {
+            Ok(()) => self.backoff.succeeded(),
+            Err(_) => self.backoff.failed(),
+        }
+    }
+
+    fn write(&mut self, line: &Line) -> io::Result<()> {
+        self.write_lines(std::slice::from_ref(line), line.encoded)
+    }
+
+    /// Write several lines at once, where `encoded` is all of them encoded one after the other
+    pub fn write_lines(&mut self, lines: &[Line], encoded: &[u8]) -> io::Result<()> {
+        if self.session.is_none() && self.backoff.ready() {
+            self.try_connect();
+        }
+
+        if let Some(session) = self.session.as_mut() {
+            if session.write_all(encoded).is_ok() {
+                return Ok(());
+            }
+
+            // loggestd went away (e.g. restarted), reconnect with the same file name and replay
+            self.session = None;
+            self.broken = true;
+            lines.iter().for_each(|line| self.replay.push(line.encoded));
+            self.try_connect();
+            return Ok(());
+        }
+
+        if self.fallback.is_none() {
+            self.fallback = Some(FallbackSink::open(
+                &self.config.fallback,
+                self.config.format,
+                &self.handshake,
+            )?);
+        }
+        let fallback = self.fallback.as_mut().unwrap();
+        for line in lines {
+            if self.broken && !fallback.is_buffer() {
+                self.replay.push(line.encoded);
+            }
+            fallback.write(line)?;
+        }
+        Ok(())
+    }
+
+    /// Close the session and drop the buffered lines, which belong to the parent process after a fork
+    fn abandon(&mut self) {
+        self.session = None;
+        self.fallback = None;
+        self.replay.take();
+    }
+
+    /// Wait for loggestd to write the lines sent so far, delivering the buffered ones first. Fails if loggestd
+    /// is unreachable.
+    pub fn sync(&mut self, durable: bool) -> io::Result<()> {
+        let pending = self.request_sync(durable)?;
+        self.wait_sync(&pending, Instant::now() + session::SYNC_TIMEOUT)
+    }
+
+    /// Ask loggestd to acknowledge once it wrote the lines sent so far, delivering the buffered ones first.
+    /// Fails if loggestd is unreachable.
+    pub fn request_sync(&mut self, durable: bool) -> io::Result<PendingSync> {
+        if self.session.is_none() && (self.fallback.is_some() || !self.replay.is_empty()) {
+            self.try_connect();
+        }
+
+        let result = match self.session.as_mut() {
+            Some(session) => session.request_sync(durable),
+            None => {
+                if let Some(fallback) = self.fallback.as_mut() {
+                    fallback.flush().ok();
+                }
+                Err(io::Error::new(io::ErrorKind::NotConnected, "loggestd is unreachable"))
+            }
+        };
+        self.disconnect_on_error(result)
+    }
+
+    /// Wait until `deadline` for loggestd to acknowledge a sync, disconnecting if it does not
+    pub fn wait_sync(&mut self, pending: &PendingSync, deadline: Instant) -> io::Result<()> {
+        let result = pending.wait(deadline);
+        self.disconnect_on_error(result)
+    }
+
+    /// Disconnect after failing to wait for `pending` without the output, unless connected again meanwhile
+    pub fn sync_failed(&mut self, pending: &PendingSync) {
+        if self.session.as_ref().is_some_and(|session| session.requested(pending)) {
+            self.disconnect();
+        }
+    }
+
+    fn disconnect_on_error<R>(&mut self, result: io::Result<R>) -> io::Result<R> {
+        if result.is_err() {
+            self.disconnect();
+        }
+        result
+    }
+
+    fn disconnect(&mut self) {
+        self.session = None;
+        self.broken = true;
+    }
+}
+
+impl Drop for ThreadOutput {
+    fn drop(&mut self) {
+        // Last chance to deliver the buffered lines
+        if self.session.is_none() && (self.fallback.is_some() || !self.replay.is_empty()) {
+            self.connect().ok();
+        }
+    }
+}
+
+/// Get the system thread ID, including for the main thread.
+fn get_thread_id_always() -> usize {
+    #[cfg(target_os = "linux")]
+    return nix::unistd::gettid().as_raw() as usize;
+
+    #[cfg(all(not(target_os = "linux"), unix))]
+    return nix::sys::pthread::pthread_self() as usize;
+
+    #[cfg(windows)]
+    return unsafe { GetCurrentThreadId() } as usize;
 }
 
 /// Get the system thread ID. The function returns None for the main thread.
@@ -29,50 +286,270 @@ fn get_thread_id() -> Option<usize> {
         return None;
     }
 
-    #[cfg(target_os = "linux")]
-    return Some(nix::unistd::gettid().as_raw() as usize);
+    Some(get_thread_id_always())
+}
 
-    #[cfg(all(not(target_os = "linux"), unix))]
-    return Some(nix::sys::pthread::pthread_self() as usize);
+/// Make a thread name safe to use as part of a file name
+fn sanitize_thread_name(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => c,
+            _ => '_',
+        })
+        .collect()
+}
 
-    #[cfg(windows)]
-    return Some(unsafe { GetCurrentThreadId() } as usize);
+/// Describe the session of the current thread
+pub(crate) fn thread_handshake(config: &Config) -> protocol::Handshake {
+    protocol::Handshake {
+        filename: get_thread_file(config),
+        thread_name: std::thread::current().name().map(str::to_owned),
+        thread_id: get_thread_id_always() as u64,
+        precision: config.precision,
+        origin: origin().1,
+        ..Default::default()
+    }
+}
+
+/// The time since the epoch
+pub(crate) fn now() -> Result<Duration, SystemTimeError> {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+}
+
+fn origin() -> &'static (Instant, u64) {
+    let mut origin = ORIGIN.load(Ordering::Acquire);
+    if origin.is_null() {
+        let new = Box::into_raw(Box::new((Instant::now(), now().map_or(0, |now| now.as_nanos() as u64))));
+        origin = match ORIGIN.compare_exchange(ptr::null_mut(), new, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => new,
+            // Set by another thread in the meantime
+            Err(current) => {
+                drop(unsafe { Box::from_raw(new) });
+                current
+            }
+        };
+    }
+    unsafe { &*origin }
+}
+
+/// Start the sequence over in a forked process, with an origin of its own. The origin of the parent is leaked.
+pub(crate) fn reset_sequence() {
+    ORIGIN.store(ptr::null_mut(), Ordering::Release);
+    SEQUENCE.store(0, Ordering::Relaxed);
+}
+
+/// Take the next sequence number of the process, with the time since the origin
+pub(crate) fn sequence() -> Sequence {
+    let (start, _) = origin();
+    Sequence {
+        number: SEQUENCE.fetch_add(1, Ordering::Relaxed),
+        monotonic: start.elapsed().as_nanos() as u64,
+    }
+}
+
+/// The base file name of the current process. Forked processes must not share the files of their parent.
+pub(crate) fn process_filename(config: &Config) -> String {
+    if fork::generation() > 0 {
+        format!("{}.{}", config.base_filename, std::process::id())
+    } else {
+        config.base_filename.clone()
+    }
 }
 
-fn get_thread_file(filename: &OsStr) -> OsString {
-    let mut result = OsString::from(filename);
-    if let Some(tid) = get_thread_id() {
-        result.push(format!(".{}", tid));
+fn get_thread_file(config: &Config) -> String {
+    let filename = &process_filename(config);
+    match config.thread_file_naming {
+        ThreadFileNaming::ThreadId => match get_thread_id() {
+            Some(tid) => format!("{}.{}", filename, tid),
+            None => filename.clone(),
+        },
+        ThreadFileNaming::AlwaysThreadId => format!("{}.{}", filename, get_thread_id_always()),
+        ThreadFileNaming::ThreadName => match std::thread::current().name() {
+            Some("main") => filename.clone(),
+            Some(name) => format!("{}.{}.{}", filename, sanitize_thread_name(name), get_thread_id_always()),
+            None => format!("{}.{}", filename, get_thread_id_always()),
+        },
+        ThreadFileNaming::Custom(f) => f(filename),
     }
-    result
 }
 
 pub fn log(record: &Record) {
+    // Logged by another thread while `loggest` is being initialized
+    let config = match config() {
+        Some(config) => config,
+        None => return,
+    };
+    if config.capture {
+        testing::capture(record);
+        return;
+    }
+    if LOGGING.with(|logging| logging.replace(true)) {
+        return;
+    }
+    let _guard = LoggingGuard;
+    if config.remote_control {
+        // Forked processes start their own control thread
+        control::start();
+    }
+    // Written synchronously if the writer thread cannot be started
+    if config.background.is_some() && background::log(config, record) {
+        return;
+    }
+
     OUTPUT
         .with(|output| -> Result<(), Ignore> {
-            if output.borrow().is_none() {
-                let filename = get_thread_file(unsafe { &CONFIG.as_ref().unwrap().base_filename });
-
-                let session = session::Session::connect()?.establish(filename.to_str().unwrap())?;
-
-                output.replace(Some(session));
+            let mut output = output.borrow_mut();
+            discard_inherited(&mut output);
+            if output.as_ref().is_some_and(|output| !ptr::eq(output.config, config)) {
+                // Made before `Builder::reinit`, connect again with the new configuration
+                output.take();
             }
+            let output =
+                output.get_or_insert_with(|| register(ThreadOutput::new(config, thread_handshake(config))));
+            let mut output = lock(&output.output);
 
-            let mut borrow = output.borrow_mut();
-            let session = borrow.as_mut().unwrap();
+            let now = now()?;
+            let mut encoded = std::mem::take(&mut output.buffer);
+            encoded.clear();
+            let timestamp = output.handshake.precision.timestamp(now);
+            protocol::write_record(
+                &mut encoded,
+                timestamp,
+                output.handshake.thread_id,
+                record,
+                config.location,
+            );
 
-            let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
-            let now = now.as_millis() as u64;
-            session.write_all(&now.to_le_bytes())?;
-            writeln!(session, "[{}] {} -- {}", record.level(), record.target(), record.args())?;
-            Ok(())
+            let result = output.write(&Line {
+                timestamp: now.as_millis() as u64,
+                text: Text::Record(record),
+                encoded: &encoded,
+            });
+            output.buffer = encoded;
+            Ok(result?)
         })
         .ok();
 }
 
-/// Flush the logger of the current thread
+/// Lock an output, recovering it if its thread panicked while logging, e.g. in the `Display` implementation of
+/// an argument. Its session is closed, in case the panic left a record half written in it.
+fn lock(output: &Mutex<ThreadOutput>) -> MutexGuard<'_, ThreadOutput> {
+    output.lock().unwrap_or_else(|e| {
+        output.clear_poison();
+        let mut output = e.into_inner();
+        output.session = None;
+        output
+    })
+}
+
+fn register(output: ThreadOutput) -> SharedOutput {
+    let generation = fork::generation();
+    let config = output.config;
+    let output = Arc::new(Mutex::new(output));
+    let mut outputs = OUTPUTS.lock().unwrap();
+    outputs.retain(|(g, output)| *g == generation && output.strong_count() > 0);
+    outputs.push((generation, Arc::downgrade(&output)));
+    SharedOutput {
+        generation,
+        config,
+        output,
+    }
+}
+
+/// Drop the output of the current thread if it was inherited from the parent process, without sending anything
+fn discard_inherited(output: &mut Option<SharedOutput>) {
+    if output
+        .as_ref()
+        .is_none_or(|output| output.generation == fork::generation())
+    {
+        return;
+    }
+
+    let output = output.take().unwrap().output;
+    let abandoned = match output.try_lock() {
+        Ok(mut output) => {
+            output.abandon();
+            true
+        }
+        Err(_) => false,
+    };
+    if !abandoned {
+        // Locked by a thread of the parent process, which does not exist in this one
+        std::mem::forget(output);
+    }
+}
+
+/// Close the session of the current thread, sending its buffered lines.
+///
+/// Use `log::logger().flush()` to wait for the lines of all threads to be written by loggestd.
 pub fn flush() {
+    let output = OUTPUT.with(|output| {
+        let mut output = output.borrow_mut();
+        discard_inherited(&mut output);
+        output.take()
+    });
+    drop(output);
+    background::flush();
+}
+
+/// Wait for loggestd to write the lines of all threads
+pub(crate) fn flush_all() {
+    let generation = fork::generation();
+    let outputs: Vec<_> = OUTPUTS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(g, _)| *g == generation)
+        .filter_map(|(_, output)| output.upgrade())
+        .collect();
+
+    // Requested from all threads before waiting, so that a stalled loggestd delays the flush by a single timeout.
+    // The outputs are not locked while waiting, so that their threads may keep logging, e.g. an embedded loggestd
+    // which logs while handling the syncs.
+    let pending: Vec<_> = outputs
+        .iter()
+        .filter_map(|output| Some((output, lock(output).request_sync(false).ok()?)))
+        .collect();
+    let deadline = Instant::now() + session::SYNC_TIMEOUT;
+    for (output, pending) in pending {
+        if pending.wait(deadline).is_err() {
+            // Falls back like a thread whose own sync failed
+            lock(output).sync_failed(&pending);
+        }
+    }
+    background::sync(false).ok();
+}
+
+/// Wait for loggestd to write the lines of the current thread and fsync them, e.g. for audit logs which must be
+/// durable before going on. Fails if they could not be delivered to loggestd.
+///
+/// In background mode, waits for the lines of all threads.
+pub fn sync() -> io::Result<()> {
+    let config = match config() {
+        Some(config) => config,
+        None => return Ok(()),
+    };
+    if config.background.is_some() && background::is_running() {
+        return background::sync(true);
+    }
+
     OUTPUT.with(|output| {
-        output.replace(None);
+        let mut output = output.borrow_mut();
+        discard_inherited(&mut output);
+        match &*output {
+            Some(output) => lock(&output.output).sync(true),
+            None => Ok(()),
+        }
     })
 }
+
+#[cfg(test)]
+mod test {
+    use super::sanitize_thread_name;
+
+    #[test]
+    fn test_sanitize_thread_name() {
+        assert_eq!(sanitize_thread_name("worker-3"), "worker-3");
+        assert_eq!(sanitize_thread_name("tokio/runtime.1 ä"), "tokio_runtime_1__");
+    }
+}
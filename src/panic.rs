@@ -0,0 +1,67 @@
+//! Logs panics, so that crash investigations can start from the log files.
+
+use crate::output;
+use log::{Level, Record};
+use std::backtrace::{Backtrace, BacktraceStatus};
+use std::panic::{self, PanicHookInfo};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+/// Install the panic hook, unless already installed. The previous hook is called afterwards.
+pub fn install() {
+    if INSTALLED.swap(true, Ordering::AcqRel) {
+        return;
+    }
+
+    let previous = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        log_panic(info);
+        previous(info);
+    }));
+}
+
+/// The message of a panic, as passed to `panic!`
+fn payload<'a>(info: &'a PanicHookInfo) -> &'a str {
+    let payload = info.payload();
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("Box<dyn Any>")
+}
+
+/// Log the panic at Error level, with a backtrace if enabled by `RUST_BACKTRACE`, then flush the sessions of all
+/// threads
+fn log_panic(info: &PanicHookInfo) {
+    // The thread panicked while logging, e.g. in the `Display` implementation of an argument
+    if output::is_logging() {
+        return;
+    }
+
+    let thread = thread::current();
+    let location = info.location();
+    let backtrace = Backtrace::capture();
+    let backtrace = match backtrace.status() {
+        BacktraceStatus::Captured => format!("\nstack backtrace:\n{}", backtrace),
+        _ => String::new(),
+    };
+
+    log::logger().log(
+        &Record::builder()
+            .level(Level::Error)
+            .target("panic")
+            .file(location.map(|location| location.file()))
+            .line(location.map(|location| location.line()))
+            .args(format_args!(
+                "thread '{}' panicked at {}:\n{}{}",
+                thread.name().unwrap_or("<unnamed>"),
+                location.map_or_else(|| "<unknown>".to_owned(), ToString::to_string),
+                payload(info),
+                backtrace
+            ))
+            .build(),
+    );
+    output::flush_all();
+}
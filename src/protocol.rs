@@ -0,0 +1,46 @@
+//! Encoding of `log` records in the protocol between `loggest` and `loggestd`, defined by [`loggest_protocol`].
+
+use log::Record;
+use loggest_protocol::{LogEncoder, SyncRequest};
+use std::fmt::Write as _;
+
+pub use loggest_protocol::{Handshake, Precision, Reply};
+
+/// Encode a log record at the end of `buf`, leaving out its source location unless `location` is set
+pub fn write_record(buf: &mut Vec<u8>, timestamp: u64, thread_id: u64, record: &Record, location: bool) {
+    let (line, module, file) = if location {
+        (record.line(), record.module_path(), record.file())
+    } else {
+        (None, None, None)
+    };
+    let mut encoder = LogEncoder::begin(
+        buf,
+        &loggest_protocol::Record {
+            timestamp,
+            level: record.level() as u8,
+            thread_id,
+            line: line.unwrap_or(0),
+            target: record.target().as_bytes(),
+            module: module.unwrap_or("").as_bytes(),
+            file: file.unwrap_or("").as_bytes(),
+            ..Default::default()
+        },
+    );
+    write!(encoder, "{}", record.args()).ok();
+    encoder.sequence(&crate::output::sequence());
+    crate::context::with(|context| {
+        for (key, value) in context {
+            encoder.context(key.as_bytes(), value.as_bytes());
+        }
+    });
+
+    #[cfg(feature = "kv")]
+    crate::fields::write_fields(&mut encoder, record.key_values());
+
+    encoder.end();
+}
+
+/// Encode a sync record at the end of `buf`
+pub fn write_sync(buf: &mut Vec<u8>, id: u64, durable: bool) {
+    SyncRequest { id, durable }.encode(buf);
+}
@@ -1,62 +1,186 @@
-use bytes::{BigEndian, ByteOrder};
-use std::env;
-use std::io::{self, Write};
+use crate::protocol;
+use std::io::{self, Read, Write};
 #[cfg(windows)]
 use std::net::TcpStream;
 #[cfg(unix)]
 use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long to wait for loggestd to acknowledge a sync
+pub const SYNC_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A connection to loggestd whose reads can time out, and which can be read by another thread than the one
+/// writing to it
+pub trait Transport: Read + Write + Sized {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    fn try_clone(&self) -> io::Result<Self>;
+}
+
+#[cfg(unix)]
+impl Transport for UnixStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        UnixStream::try_clone(self)
+    }
+}
+
+#[cfg(windows)]
+impl Transport for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+}
 
 pub struct Session<T>
 where
-    T: Write,
+    T: Read + Write,
 {
     transport: T,
 }
 
 #[cfg(unix)]
 impl Session<UnixStream> {
-    pub fn connect() -> Result<Session<UnixStream>, io::Error> {
-        UnixStream::connect(env::var("LOGGESTD_SOCKET").unwrap_or_else(|_| "/run/loggestd.sock".into()))
-            .map(|transport| Session { transport })
+    pub fn connect(address: &str) -> Result<Session<UnixStream>, io::Error> {
+        Self::connect_with_read_timeout(address, Some(SYNC_TIMEOUT))
+    }
+
+    pub fn connect_with_read_timeout(
+        address: &str,
+        timeout: Option<Duration>,
+    ) -> Result<Session<UnixStream>, io::Error> {
+        let transport = UnixStream::connect(address)?;
+        transport.set_read_timeout(timeout)?;
+        Ok(Session { transport })
     }
 }
 
 #[cfg(windows)]
 impl Session<TcpStream> {
-    pub fn connect() -> Result<Session<TcpStream>, io::Error> {
-        TcpStream::connect(env::var("LOGGESTD_SOCKET").unwrap_or_else(|_| "127.0.0.1:1099".into()))
-            .map(|transport| Session { transport })
+    pub fn connect(address: &str) -> Result<Session<TcpStream>, io::Error> {
+        Self::connect_with_read_timeout(address, Some(SYNC_TIMEOUT))
+    }
+
+    pub fn connect_with_read_timeout(
+        address: &str,
+        timeout: Option<Duration>,
+    ) -> Result<Session<TcpStream>, io::Error> {
+        let transport = TcpStream::connect(address)?;
+        transport.set_read_timeout(timeout)?;
+        Ok(Session { transport })
     }
 }
 
 impl<T> Session<T>
 where
-    T: Write,
+    T: Transport,
 {
-    pub fn establish(mut self, filename: &str) -> Result<EstablishedSession<T>, io::Error> {
-        let filename = filename.as_bytes();
-        let mut buffer = [0; 2];
-
-        BigEndian::write_u16(&mut buffer, filename.len() as u16);
-        self.transport.write_all(&buffer)?;
-        self.transport.write_all(filename)?;
+    pub fn establish(mut self, handshake: &protocol::Handshake) -> Result<EstablishedSession<T>, io::Error> {
+        handshake.write(&mut self.transport)?;
 
         Ok(EstablishedSession {
+            acks: Arc::new(Mutex::new(Acks {
+                transport: self.transport.try_clone()?,
+                acked: 0,
+            })),
             transport: self.transport,
+            last_sync: 0,
         })
     }
 }
 
 pub struct EstablishedSession<T>
 where
-    T: Write,
+    T: Read + Write,
 {
     transport: T,
+    last_sync: u64,
+    /// Read by the threads waiting for syncs, so that they do not hold up the thread writing to the session
+    acks: Arc<Mutex<Acks<T>>>,
+}
+
+/// The acknowledgments of the syncs of a session
+struct Acks<T> {
+    transport: T,
+    /// The last sync acknowledged, which implies the previous ones
+    acked: u64,
+}
+
+/// A sync requested from loggestd, which can be waited for without the session
+pub struct PendingSync<T> {
+    acks: Arc<Mutex<Acks<T>>>,
+    id: u64,
+}
+
+impl<T> EstablishedSession<T>
+where
+    T: Read + Write,
+{
+    /// Read the body of the next reply from loggestd into `buf`
+    pub fn read_reply(&mut self, buf: &mut Vec<u8>) -> Result<(), io::Error> {
+        loggest_protocol::read_frame(&mut self.transport, buf)
+    }
+
+    /// Ask loggestd to acknowledge once it wrote everything sent so far, and fsynced it if `durable`
+    pub fn request_sync(&mut self, durable: bool) -> Result<PendingSync<T>, io::Error> {
+        self.last_sync += 1;
+        let mut buf = Vec::new();
+        protocol::write_sync(&mut buf, self.last_sync, durable);
+        self.transport.write_all(&buf)?;
+
+        Ok(PendingSync {
+            acks: self.acks.clone(),
+            id: self.last_sync,
+        })
+    }
+
+    /// Whether `pending` was requested from this session
+    pub fn requested(&self, pending: &PendingSync<T>) -> bool {
+        Arc::ptr_eq(&self.acks, &pending.acks)
+    }
+}
+
+impl<T> PendingSync<T>
+where
+    T: Transport,
+{
+    /// Wait until `deadline` for loggestd to acknowledge the sync
+    pub fn wait(&self, deadline: Instant) -> Result<(), io::Error> {
+        let mut acks = self
+            .acks
+            .lock()
+            .map_err(|_| io::Error::other("Acknowledgments lock poisoned"))?;
+        let mut buf = Vec::new();
+        while acks.acked < self.id {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "loggestd did not acknowledge the sync",
+                ));
+            }
+            acks.transport.set_read_timeout(Some(remaining))?;
+            loggest_protocol::read_frame(&mut acks.transport, &mut buf)?;
+
+            if let Ok(Some(protocol::Reply::Ack(id))) = protocol::Reply::decode(&buf) {
+                acks.acked = acks.acked.max(id);
+            }
+        }
+        Ok(())
+    }
 }
 
 impl<T> Write for EstablishedSession<T>
 where
-    T: Write,
+    T: Read + Write,
 {
     fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
         self.transport.write(buf)
@@ -0,0 +1,91 @@
+//! Duplicates records to the standard error, e.g. to see them in the terminal during development.
+
+use crate::fallback::write_text;
+use crate::output::now;
+use crate::FormatFn;
+use log::{Level, LevelFilter, Record};
+use std::io::{self, Write};
+
+/// How records are duplicated to the standard error, see [`Builder::tee_stderr`](crate::Builder::tee_stderr)
+pub(crate) struct Tee {
+    pub level: LevelFilter,
+    pub format: FormatFn,
+    /// Color the lines by level with ANSI escape codes
+    pub colors: bool,
+}
+
+fn color(level: Level) -> &'static str {
+    match level {
+        Level::Error => "1;31",
+        Level::Warn => "33",
+        Level::Info => "32",
+        Level::Debug => "36",
+        Level::Trace => "90",
+    }
+}
+
+impl Tee {
+    pub fn enabled(&self, level: Level) -> bool {
+        level <= self.level
+    }
+
+    pub fn log(&self, record: &Record) {
+        if !self.enabled(record.level()) {
+            return;
+        }
+
+        let mut text = Vec::new();
+        if let Ok(now) = now() {
+            if self.write(&mut text, now.as_millis() as u64, record).is_ok() {
+                io::stderr().lock().write_all(&text).ok();
+            }
+        }
+    }
+
+    fn write<W: Write>(&self, w: &mut W, timestamp: u64, record: &Record) -> io::Result<()> {
+        if !self.colors {
+            return write_text(w, timestamp, record, self.format);
+        }
+
+        let mut text = Vec::new();
+        write_text(&mut text, timestamp, record, self.format)?;
+        // The trailing newline is written after resetting the color
+        text.pop();
+        write!(w, "\x1b[{}m", color(record.level()))?;
+        w.write_all(&text)?;
+        w.write_all(b"\x1b[0m\n")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Tee;
+    use log::{Level, LevelFilter, Record};
+
+    #[test]
+    fn test_colors() {
+        let record = Record::builder()
+            .level(Level::Warn)
+            .target("test")
+            .args(format_args!("hello"))
+            .build();
+        let mut tee = Tee {
+            level: LevelFilter::Debug,
+            format: crate::default_format,
+            colors: true,
+        };
+
+        let mut text = Vec::new();
+        tee.write(&mut text, 1_528_377_136_413, &record).unwrap();
+        assert_eq!(
+            text,
+            b"\x1b[33m2018-06-07 13:12:16.413 [WARN] test -- hello\x1b[0m\n".to_vec()
+        );
+
+        tee.colors = false;
+        let mut text = Vec::new();
+        tee.write(&mut text, 1_528_377_136_413, &record).unwrap();
+        assert_eq!(text, b"2018-06-07 13:12:16.413 [WARN] test -- hello\n".to_vec());
+        assert!(!tee.enabled(Level::Trace));
+    }
+}
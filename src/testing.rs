@@ -0,0 +1,155 @@
+//! Capture of records in memory, for asserting on log output in tests without loggestd.
+//!
+//! Records are kept per thread, so that tests running in parallel only see their own records. They go through the
+//! encoding of the protocol, so that they are captured as loggestd would write them.
+//!
+//! # Example
+//! ```
+//! use log::{warn, Level};
+//! use loggest::assert_logged;
+//!
+//! loggest::testing::init();
+//! warn!("connection timeout after {}s", 5);
+//! assert_logged!(Level::Warn, "timeout");
+//! ```
+
+use crate::protocol;
+use crate::{config, Builder, LoggestError};
+use log::{Level, LevelFilter, Record};
+#[cfg(feature = "kv")]
+use loggest_protocol::Value;
+use std::cell::RefCell;
+
+thread_local! {
+    static CAPTURED: RefCell<Vec<CapturedRecord>> = const { RefCell::new(Vec::new()) };
+}
+
+/// A record logged by the current thread
+#[derive(Clone, Debug, PartialEq)]
+pub struct CapturedRecord {
+    pub level: Level,
+    pub target: String,
+    pub message: String,
+    pub module_path: Option<String>,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+    /// The structured key-values, formatted with `Display`
+    #[cfg(feature = "kv")]
+    pub fields: Vec<(String, String)>,
+    /// The entries of the [`context`](crate::context) of the thread
+    pub context: Vec<(String, String)>,
+}
+
+/// Install `loggest` capturing records of all levels instead of sending them to loggestd. Can be called by every
+/// test.
+///
+/// # Panics
+/// If another logger is installed, or if `loggest` was already initialized without capture.
+pub fn init() {
+    match Builder::new("loggest-test")
+        .level(LevelFilter::Trace)
+        .capture()
+        .try_init()
+    {
+        Ok(_) => (),
+        Err(LoggestError::AlreadyInitialized) => {
+            if !config().is_some_and(|config| config.capture) {
+                panic!("loggest was already initialized without capture, records would not be captured");
+            }
+        }
+        Err(e) => panic!("Failed to install loggest for testing: {}", e),
+    }
+}
+
+/// Capture a record as loggestd would receive it, encoded with its location and decoded again
+pub(crate) fn capture(record: &Record) {
+    let mut encoded = Vec::new();
+    protocol::write_record(&mut encoded, 0, 0, record, true);
+    let mut body = Vec::new();
+    loggest_protocol::read_frame(&mut encoded.as_slice(), &mut body).expect("Failed to read an encoded record");
+    let decoded = match loggest_protocol::Record::decode(&body) {
+        Ok(Some(decoded)) => decoded,
+        _ => panic!("Failed to decode an encoded record"),
+    };
+
+    let record = CapturedRecord {
+        level: Level::iter()
+            .find(|level| *level as u8 == decoded.level)
+            .expect("Invalid level"),
+        target: to_string(decoded.target),
+        message: to_string(decoded.message),
+        module_path: to_option(decoded.module),
+        file: to_option(decoded.file),
+        line: Some(decoded.line).filter(|&line| line != 0),
+        #[cfg(feature = "kv")]
+        fields: decoded
+            .fields
+            .iter()
+            .map(|(key, value)| (to_string(key), value_to_string(value)))
+            .collect(),
+        context: decoded
+            .context
+            .iter()
+            .map(|(key, value)| (to_string(key), to_string(value)))
+            .collect(),
+    };
+    CAPTURED.with(|captured| captured.borrow_mut().push(record));
+}
+
+fn to_string(s: &[u8]) -> String {
+    String::from_utf8_lossy(s).into_owned()
+}
+
+/// Empty strings stand for missing ones in the protocol
+fn to_option(s: &[u8]) -> Option<String> {
+    Some(to_string(s)).filter(|s| !s.is_empty())
+}
+
+#[cfg(feature = "kv")]
+fn value_to_string(value: &Value) -> String {
+    match value {
+        Value::Str(s) => to_string(s),
+        Value::I64(value) => value.to_string(),
+        Value::U64(value) => value.to_string(),
+        Value::F64(value) => value.to_string(),
+        Value::Bool(value) => value.to_string(),
+    }
+}
+
+/// The records logged by the current thread so far
+pub fn captured() -> Vec<CapturedRecord> {
+    CAPTURED.with(|captured| captured.borrow().clone())
+}
+
+/// Take the records logged by the current thread so far, so that later assertions only see newer ones
+pub fn take() -> Vec<CapturedRecord> {
+    CAPTURED.with(|captured| captured.take())
+}
+
+/// Whether the current thread logged a record of `level` whose message contains `text`
+pub fn logged(level: Level, text: &str) -> bool {
+    CAPTURED.with(|captured| {
+        captured
+            .borrow()
+            .iter()
+            .any(|record| record.level == level && record.message.contains(text))
+    })
+}
+
+/// Assert that the current thread logged a record of the given level whose message contains the given text.
+///
+/// Requires [`testing::init`](crate::testing::init).
+#[macro_export]
+macro_rules! assert_logged {
+    ($level:expr, $text:expr) => {{
+        let (level, text) = ($level, $text);
+        if !$crate::testing::logged(level, text) {
+            panic!(
+                "No {} record containing {:?} was logged, captured: {:#?}",
+                level,
+                text,
+                $crate::testing::captured()
+            );
+        }
+    }};
+}
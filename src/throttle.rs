@@ -0,0 +1,279 @@
+//! Rate limiting of records per callsite, and sampling of records per target, for hot log sites which would
+//! otherwise flood loggestd and fill the disk.
+
+use crate::filter::target_matches;
+use log::{Level, Record};
+use std::cell::Cell;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const WINDOW: Duration = Duration::from_secs(1);
+
+thread_local! {
+    /// The state of the xorshift generator used for sampling, never 0
+    static RANDOM: Cell<u64> = Cell::new(seed());
+}
+
+const SHARDS: usize = 32;
+
+/// The rate limited callsites with their windows, by the hash of their target and location
+type Callsites = BTreeMap<u64, (Callsite, Window)>;
+
+/// [`Callsites`] sharded by their hash, so that threads logging from different callsites rarely wait for each other
+static CALLSITES: [Mutex<Callsites>; SHARDS] = [const { Mutex::new(BTreeMap::new()) }; SHARDS];
+
+/// Holds [`CALLSITES`] while forking, so that the child does not inherit them locked by another thread
+pub(crate) struct ForkGuard {
+    _callsites: [MutexGuard<'static, Callsites>; SHARDS],
+}
+
+pub(crate) fn lock_for_fork() -> ForkGuard {
+    ForkGuard {
+        _callsites: std::array::from_fn(|i| CALLSITES[i].lock().unwrap_or_else(|e| e.into_inner())),
+    }
+}
+
+/// The first record of a rate limited callsite, for the summary of the records suppressed
+#[derive(Clone, Debug)]
+pub(crate) struct Callsite {
+    pub level: Level,
+    pub target: String,
+    pub module_path: Option<String>,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+}
+
+impl Callsite {
+    fn new(record: &Record) -> Self {
+        Self {
+            level: record.level(),
+            target: record.target().to_owned(),
+            module_path: record.module_path().map(str::to_owned),
+            file: record.file().map(str::to_owned),
+            line: record.line(),
+        }
+    }
+}
+
+/// A setting per target, from the most specific target to the least so that the first match wins
+#[derive(Debug, Clone)]
+pub(crate) struct Targets<T> {
+    entries: Vec<(Option<String>, T)>,
+}
+
+impl<T: Copy> Targets<T> {
+    pub fn new() -> Self {
+        Self { entries: Vec::new() }
+    }
+
+    /// Set the value of `target` and its submodules, or of all targets if `target` is `None`
+    pub fn insert(&mut self, target: Option<String>, value: T) {
+        if let Some(entry) = self.entries.iter_mut().find(|(t, _)| *t == target) {
+            entry.1 = value;
+        } else {
+            self.entries.push((target, value));
+            self.entries
+                .sort_by_key(|(t, _)| std::cmp::Reverse(t.as_ref().map_or(0, |t| t.len() + 1)));
+        }
+    }
+
+    pub fn get(&self, target: &str) -> Option<T> {
+        self.entries
+            .iter()
+            .find(|(t, _)| t.as_deref().is_none_or(|t| target_matches(t, target)))
+            .map(|&(_, value)| value)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = T> + '_ {
+        self.entries.iter().map(|&(_, value)| value)
+    }
+}
+
+/// The records of a callsite in the current second
+#[derive(Debug)]
+struct Window {
+    start: Instant,
+    count: u32,
+    suppressed: u64,
+}
+
+impl Window {
+    fn new(now: Instant) -> Self {
+        Self {
+            start: now,
+            count: 0,
+            suppressed: 0,
+        }
+    }
+
+    /// Count a record, returning the number suppressed since the previous window if it is let through
+    fn admit(&mut self, now: Instant, limit: u32) -> Option<u64> {
+        let mut suppressed = 0;
+        if now.duration_since(self.start) >= WINDOW {
+            suppressed = std::mem::take(&mut self.suppressed);
+            *self = Self::new(now);
+        }
+
+        if self.count < limit {
+            self.count += 1;
+            Some(suppressed)
+        } else {
+            self.suppressed += suppressed + 1;
+            None
+        }
+    }
+}
+
+/// Whether a record is logged, and how many of its callsite were suppressed before it
+#[derive(Debug, PartialEq)]
+pub(crate) enum Admit {
+    Log { suppressed: u64 },
+    Drop,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Throttle {
+    /// The records per second of each callsite
+    pub rate_limits: Targets<u32>,
+    /// The probability of each record being logged
+    pub samples: Targets<f64>,
+}
+
+impl Throttle {
+    pub fn admit(&self, record: &Record) -> Admit {
+        if let Some(probability) = self.samples.get(record.target()) {
+            if random() >= probability {
+                return Admit::Drop;
+            }
+        }
+
+        let limit = match self.rate_limits.get(record.target()) {
+            Some(limit) => limit,
+            None => return Admit::Log { suppressed: 0 },
+        };
+        let hash = callsite(record);
+        let mut callsites = CALLSITES[hash as usize % SHARDS]
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        let (_, window) = callsites
+            .entry(hash)
+            .or_insert_with(|| (Callsite::new(record), Window::new(now)));
+        match window.admit(now, limit) {
+            Some(suppressed) => Admit::Log { suppressed },
+            None => Admit::Drop,
+        }
+    }
+}
+
+/// Take the number of records suppressed by each callsite since the last one let through, so that callsites which
+/// went quiet are not left unreported
+pub(crate) fn take_suppressed() -> Vec<(Callsite, u64)> {
+    let mut suppressed = Vec::new();
+    for callsites in &CALLSITES {
+        let mut callsites = callsites.lock().unwrap_or_else(|e| e.into_inner());
+        for (callsite, window) in callsites.values_mut() {
+            if window.suppressed > 0 {
+                suppressed.push((callsite.clone(), std::mem::take(&mut window.suppressed)));
+            }
+        }
+    }
+    suppressed
+}
+
+fn callsite(record: &Record) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (record.target(), record.module_path(), record.file(), record.line()).hash(&mut hasher);
+    hasher.finish()
+}
+
+fn seed() -> u64 {
+    let mut hasher = DefaultHasher::new();
+    std::thread::current().id().hash(&mut hasher);
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .hash(&mut hasher);
+    hasher.finish() | 1
+}
+
+/// A uniformly distributed number in `[0, 1)`
+fn random() -> f64 {
+    RANDOM.with(|state| {
+        let mut x = state.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        state.set(x);
+        (x >> 11) as f64 / (1u64 << 53) as f64
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_window() {
+        let start = Instant::now();
+        let mut window = Window::new(start);
+        assert_eq!(window.admit(start, 2), Some(0));
+        assert_eq!(window.admit(start, 2), Some(0));
+        assert_eq!(window.admit(start, 2), None);
+        assert_eq!(window.admit(start + Duration::from_millis(999), 2), None);
+        assert_eq!(window.admit(start + WINDOW, 2), Some(2));
+        assert_eq!(window.admit(start + WINDOW, 2), Some(0));
+        assert_eq!(window.admit(start + WINDOW * 3, 2), Some(0));
+    }
+
+    #[test]
+    fn test_take_suppressed() {
+        let mut throttle = Throttle {
+            rate_limits: Targets::new(),
+            samples: Targets::new(),
+        };
+        throttle.rate_limits.insert(Some("quiet".to_owned()), 1);
+        let record = Record::builder().target("quiet").line(Some(7)).build();
+        let suppressed = || -> Vec<_> {
+            take_suppressed()
+                .into_iter()
+                .filter(|(callsite, _)| callsite.target == "quiet")
+                .map(|(callsite, suppressed)| (callsite.line, suppressed))
+                .collect()
+        };
+
+        assert_eq!(throttle.admit(&record), Admit::Log { suppressed: 0 });
+        assert_eq!(suppressed(), []);
+        assert_eq!(throttle.admit(&record), Admit::Drop);
+        assert_eq!(throttle.admit(&record), Admit::Drop);
+        assert_eq!(suppressed(), [(Some(7), 2)]);
+        // Not reported twice
+        assert_eq!(suppressed(), []);
+    }
+
+    #[test]
+    fn test_sampling() {
+        let mut throttle = Throttle {
+            rate_limits: Targets::new(),
+            samples: Targets::new(),
+        };
+        throttle.samples.insert(Some("hot".to_owned()), 0.0);
+        throttle.samples.insert(Some("hot::kept".to_owned()), 1.0);
+        let admit = |throttle: &Throttle, target| throttle.admit(&Record::builder().target(target).build());
+
+        assert_eq!(admit(&throttle, "hot"), Admit::Drop);
+        assert_eq!(admit(&throttle, "hot::kept"), Admit::Log { suppressed: 0 });
+        assert_eq!(admit(&throttle, "hotter"), Admit::Log { suppressed: 0 });
+
+        throttle.samples.insert(None, 0.5);
+        let logged = (0..10_000).filter(|_| admit(&throttle, "cold") != Admit::Drop).count();
+        assert!((4_000..6_000).contains(&logged), "{}", logged);
+    }
+}
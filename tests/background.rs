@@ -0,0 +1,43 @@
+use log::info;
+use loggest::{Builder, Fallback, Overflow};
+use std::fs;
+use std::thread;
+
+#[test]
+fn test_background_block() {
+    let path = std::env::temp_dir().join(format!("loggest-background-{}.log", std::process::id()));
+    fs::remove_file(&path).ok();
+    let flush = Builder::new("test-background")
+        .socket("/nonexistent")
+        .fallback(Fallback::File(path.clone()))
+        .background(2, Overflow::Block)
+        .init()
+        .unwrap();
+
+    let threads: Vec<_> = (0..4)
+        .map(|thread| {
+            thread::spawn(move || {
+                for i in 0..100 {
+                    info!("thread {} record {}", thread, i);
+                }
+                loggest::flush();
+            })
+        })
+        .collect();
+    threads.into_iter().for_each(|thread| thread.join().unwrap());
+    drop(flush);
+
+    let text = fs::read_to_string(&path).unwrap();
+    fs::remove_file(&path).ok();
+    assert_eq!(loggest::dropped_records(), 0);
+    assert_eq!(text.lines().count(), 400);
+    for thread in 0..4 {
+        // The records of each thread are delivered in order
+        let records: Vec<_> = text
+            .lines()
+            .filter_map(|line| line.split_once(&format!("thread {} record ", thread)))
+            .map(|(_, i)| i.parse::<u32>().unwrap())
+            .collect();
+        assert_eq!(records, (0..100).collect::<Vec<_>>());
+    }
+}
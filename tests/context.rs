@@ -0,0 +1,32 @@
+use log::info;
+use loggest::{Builder, Fallback};
+use std::fs;
+
+#[test]
+fn test_context_fallback() {
+    let path = std::env::temp_dir().join(format!("loggest-context-{}.log", std::process::id()));
+    fs::remove_file(&path).ok();
+    let flush = Builder::new("test-context")
+        .socket("/nonexistent")
+        .fallback(Fallback::File(path.clone()))
+        .init()
+        .unwrap();
+
+    let _request = loggest::context::push("req_id", "f3a9");
+    {
+        let _user = loggest::context::push("user", 7);
+        info!("handling");
+    }
+    info!("done");
+    loggest::flush();
+    drop(flush);
+
+    let text = fs::read_to_string(&path).unwrap();
+    fs::remove_file(&path).ok();
+    assert!(
+        text.contains("[INFO] context -- handling {req_id=f3a9 user=7}\n"),
+        "{}",
+        text
+    );
+    assert!(text.contains("[INFO] context -- done {req_id=f3a9}\n"), "{}", text);
+}
@@ -0,0 +1,169 @@
+#![cfg(unix)]
+
+use log::info;
+use loggest::{Builder, ThreadFileNaming};
+use loggest_protocol::{Handshake, Record};
+use nix::sys::signal::{kill, Signal};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::{fork, ForkResult, Pid};
+use std::io::Read;
+use std::os::unix::net::UnixListener;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The handshake and the data of each session, once it is closed
+type Sessions = Arc<Mutex<Vec<(Handshake, Vec<u8>)>>>;
+
+/// The handshake of each session, once it is established
+type Accepted = Arc<Mutex<Vec<Handshake>>>;
+
+fn listen(socket: &str) -> (Accepted, Sessions) {
+    let listener = UnixListener::bind(socket).unwrap();
+    let (accepted, sessions) = (Accepted::default(), Sessions::default());
+    let (accepting, closed) = (accepted.clone(), sessions.clone());
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let (accepted, sessions) = (accepting.clone(), closed.clone());
+            thread::spawn(move || {
+                // Closed before the handshake, e.g. by a killed child
+                let Ok(handshake) = Handshake::read(&mut stream) else {
+                    return;
+                };
+                accepted.lock().unwrap().push(handshake.clone());
+                let mut data = Vec::new();
+                stream.read_to_end(&mut data).unwrap();
+                sessions.lock().unwrap().push((handshake, data));
+            });
+        }
+    });
+    (accepted, sessions)
+}
+
+fn contains(data: &[u8], text: &str) -> bool {
+    data.windows(text.len()).any(|window| window == text.as_bytes())
+}
+
+/// The sequence number of the first record of a session
+fn first_number(mut data: &[u8]) -> u64 {
+    let mut body = Vec::new();
+    loggest_protocol::read_frame(&mut data, &mut body).unwrap();
+    Record::decode(&body).unwrap().unwrap().sequence.unwrap().number
+}
+
+/// Wait for the child to exit, killing it if it does not
+fn wait(child: Pid) {
+    let start = Instant::now();
+    while waitpid(child, Some(WaitPidFlag::WNOHANG)).unwrap() == WaitStatus::StillAlive {
+        if start.elapsed() > Duration::from_secs(10) {
+            kill(child, Signal::SIGKILL).ok();
+            panic!("The child process {} is stuck", child);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn test_fork() {
+    let socket = std::env::temp_dir().join(format!("loggest-fork-{}.sock", std::process::id()));
+    let socket = socket.to_str().unwrap();
+    std::fs::remove_file(socket).ok();
+    let (accepted, sessions) = listen(socket);
+
+    // Rate limited, so that the other thread keeps locking the callsites, and remotely controlled, so that each
+    // child starts its own control thread. All the threads of a process share the
+    // file name, which is the base file name with `.<pid>` appended in the children.
+    let _flush = Builder::new("test-fork")
+        .socket(socket)
+        .thread_file_naming(ThreadFileNaming::Custom(str::to_owned))
+        .rate_limit(u32::MAX)
+        .remote_control(true)
+        .init()
+        .unwrap();
+    info!("parent before fork");
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let busy = {
+        let stop = stop.clone();
+        thread::spawn(move || {
+            while !stop.load(Ordering::Relaxed) {
+                info!("parent busy");
+            }
+            loggest::flush();
+        })
+    };
+
+    let mut children = Vec::new();
+    for _ in 0..20 {
+        match fork().unwrap() {
+            ForkResult::Child => {
+                info!("child {}", std::process::id());
+                loggest::flush();
+                // Killed by the parent once its control thread connected
+                thread::sleep(Duration::from_secs(10));
+                unsafe { nix::libc::_exit(0) };
+            }
+            ForkResult::Parent { child } => {
+                let control = format!("test-fork.{}", child);
+                let start = Instant::now();
+                while !accepted
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .any(|handshake| handshake.control && handshake.filename == control)
+                {
+                    if start.elapsed() > Duration::from_secs(5) {
+                        kill(child, Signal::SIGKILL).ok();
+                        panic!("No control session for {}", control);
+                    }
+                    thread::sleep(Duration::from_millis(10));
+                }
+                kill(child, Signal::SIGKILL).unwrap();
+                wait(child);
+                children.push(child);
+            }
+        }
+    }
+    info!("parent after fork");
+
+    stop.store(true, Ordering::Relaxed);
+    busy.join().unwrap();
+    loggest::flush();
+    std::fs::remove_file(socket).ok();
+
+    let start = Instant::now();
+    let closed = || {
+        sessions
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(handshake, _)| !handshake.control)
+            .count()
+    };
+    while closed() < children.len() + 2 && start.elapsed() < Duration::from_secs(5) {
+        thread::sleep(Duration::from_millis(10));
+    }
+    let sessions = sessions.lock().unwrap();
+    let parent: Vec<_> = sessions
+        .iter()
+        .filter(|(handshake, _)| !handshake.control && handshake.filename == "test-fork")
+        .collect();
+    for child in &children {
+        let filename = format!("test-fork.{}", child);
+        let (handshake, data) = sessions
+            .iter()
+            .find(|(handshake, _)| !handshake.control && handshake.filename == filename)
+            .unwrap_or_else(|| panic!("No session for {}", filename));
+        assert!(contains(data, &format!("child {}", child)));
+        // The sequence of a child starts over, from an origin of its own
+        assert_eq!(first_number(data), 0);
+        assert!(handshake.origin > parent[0].0.origin);
+    }
+
+    assert_eq!(parent.len(), 2);
+    assert!(parent.iter().any(|(_, data)| contains(data, "parent after fork")));
+    // The sessions of the parent never receive the records of the children
+    assert!(parent.iter().all(|(_, data)| !contains(data, "child")));
+}
@@ -0,0 +1,34 @@
+use log::{info, LevelFilter};
+use loggest::{Builder, LoggestError};
+
+#[test]
+fn test_init() {
+    // Logged before initialization, and dropped
+    info!("too early");
+
+    assert!(matches!(
+        Builder::new("test-init").sample_target("hot", f64::NAN).try_init(),
+        Err(LoggestError::BadProbability(_))
+    ));
+
+    // Reported and ignored, instead of failing to initialize
+    std::env::set_var("LOGGEST_FILTER", "debug,hyper=loud");
+    let _flush = Builder::new("test-init").socket("/nonexistent").try_init().unwrap();
+    assert!(matches!(
+        Builder::new("test-init").try_init(),
+        Err(LoggestError::AlreadyInitialized)
+    ));
+    assert!(log::log_enabled!(log::Level::Info));
+    assert!(!log::log_enabled!(log::Level::Debug));
+
+    let _flush = Builder::new("test-reinit")
+        .socket("/nonexistent")
+        .level(LevelFilter::Debug)
+        .reinit()
+        .unwrap();
+    assert!(log::log_enabled!(log::Level::Debug));
+    info!("after reinit");
+
+    // Records would go to loggestd instead of being captured
+    assert!(std::panic::catch_unwind(loggest::testing::init).is_err());
+}
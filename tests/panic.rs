@@ -0,0 +1,30 @@
+use loggest::{Builder, Fallback};
+use std::fs;
+use std::thread;
+
+#[test]
+fn test_panic_hook() {
+    let path = std::env::temp_dir().join(format!("loggest-panic-{}.log", std::process::id()));
+    let _flush = Builder::new("test-panic")
+        .socket("/nonexistent")
+        .fallback(Fallback::File(path.clone()))
+        .panic_hook(true)
+        .init()
+        .unwrap();
+
+    let result = thread::Builder::new()
+        .name("doomed".to_owned())
+        .spawn(|| panic!("boom {}", 42))
+        .unwrap()
+        .join();
+    assert!(result.is_err());
+
+    let text = fs::read_to_string(&path).unwrap();
+    fs::remove_file(&path).ok();
+    assert!(
+        text.contains("[ERROR] panic -- thread 'doomed' panicked at tests/panic.rs:"),
+        "{}",
+        text
+    );
+    assert!(text.contains("boom 42"), "{}", text);
+}
@@ -0,0 +1,36 @@
+use log::info;
+use loggest::{Builder, Fallback};
+use std::fmt;
+use std::fs;
+use std::panic;
+
+struct Bad;
+
+impl fmt::Display for Bad {
+    fn fmt(&self, _: &mut fmt::Formatter) -> fmt::Result {
+        panic!("bad display")
+    }
+}
+
+#[test]
+fn test_panic_while_logging() {
+    let path = std::env::temp_dir().join(format!("loggest-poison-{}.log", std::process::id()));
+    fs::remove_file(&path).ok();
+    let flush = Builder::new("test-poison")
+        .socket("/nonexistent")
+        .fallback(Fallback::File(path.clone()))
+        .init()
+        .unwrap();
+
+    info!("before");
+    assert!(panic::catch_unwind(|| info!("x {}", Bad)).is_err());
+    // The thread keeps logging after the panic
+    info!("after");
+    loggest::flush();
+    drop(flush);
+
+    let text = fs::read_to_string(&path).unwrap();
+    fs::remove_file(&path).ok();
+    assert!(text.contains("poison -- before\n"), "{}", text);
+    assert!(text.contains("poison -- after\n"), "{}", text);
+}
@@ -0,0 +1,54 @@
+use log::info;
+use loggest::{Builder, Fallback, Overflow};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::thread;
+
+fn path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("loggest-reinit-{}-{}.log", name, std::process::id()));
+    fs::remove_file(&path).ok();
+    path
+}
+
+#[test]
+fn test_reinit_other_threads() {
+    let (first, second, third) = (path("first"), path("second"), path("third"));
+    let builder = |path: &PathBuf| {
+        Builder::new("test-reinit")
+            .socket("/nonexistent")
+            .fallback(Fallback::File(path.clone()))
+    };
+
+    // Logs each message it receives, from a thread which outlives every configuration
+    let (sender, receiver) = mpsc::channel::<(&str, mpsc::Sender<()>)>();
+    let worker = thread::spawn(move || {
+        for (message, done) in receiver {
+            info!("{}", message);
+            done.send(()).unwrap();
+        }
+    });
+    let log = |message| {
+        let (done, wait) = mpsc::channel();
+        sender.send((message, done)).unwrap();
+        wait.recv().unwrap();
+    };
+
+    let _flush = builder(&first).init().unwrap();
+    log("first");
+    let _flush = builder(&second).background(16, Overflow::Block).reinit().unwrap();
+    log("second");
+    // The writer switches to the new configuration too
+    let flush = builder(&third).background(16, Overflow::Block).reinit().unwrap();
+    log("third");
+    drop(sender);
+    worker.join().unwrap();
+    drop(flush);
+
+    for (path, message) in [(first, "first"), (second, "second"), (third, "third")] {
+        let text = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).ok();
+        assert_eq!(text.lines().count(), 1, "{}", text);
+        assert!(text.ends_with(&format!("[INFO] reinit -- {}\n", message)), "{}", text);
+    }
+}
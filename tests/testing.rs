@@ -0,0 +1,41 @@
+use log::{debug, info, Level};
+use loggest::{assert_logged, testing};
+use std::thread;
+
+#[test]
+fn test_capture() {
+    testing::init();
+    debug!(target: "db", "query took {}ms", 12);
+    assert_logged!(Level::Debug, "took 12ms");
+
+    let records = testing::take();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].target, "db");
+    assert_eq!(records[0].module_path.as_deref(), Some(module_path!()));
+    assert!(testing::captured().is_empty());
+}
+
+#[test]
+fn test_capture_per_thread() {
+    testing::init();
+    thread::spawn(|| info!("from another thread")).join().unwrap();
+    assert!(!testing::logged(Level::Info, "from another thread"));
+}
+
+#[test]
+#[should_panic(expected = "No WARN record")]
+fn test_assert_logged() {
+    testing::init();
+    info!("timeout");
+    assert_logged!(Level::Warn, "timeout");
+}
+
+#[test]
+fn test_capture_context() {
+    testing::init();
+    let _request = loggest::context::push("req_id", "f3a9");
+    info!("handling");
+
+    let records = testing::take();
+    assert_eq!(records[0].context, [("req_id".to_owned(), "f3a9".to_owned())]);
+}
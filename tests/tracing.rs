@@ -0,0 +1,36 @@
+#![cfg(feature = "tracing")]
+
+use log::Level;
+use loggest::{testing, LoggestLayer};
+use tracing_subscriber::layer::SubscriberExt;
+
+#[test]
+fn test_layer() {
+    testing::init();
+    let subscriber = tracing_subscriber::registry().with(LoggestLayer::new());
+    tracing::subscriber::with_default(subscriber, || {
+        let span = tracing::info_span!("request", id = 7);
+        let _entered = span.enter();
+        tracing::debug_span!("query", table = "users").in_scope(|| {
+            tracing::warn!(target: "db", rows = 3u64, slow = true, "took {}ms", 12);
+        });
+    });
+
+    let records = testing::take();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].level, Level::Warn);
+    assert_eq!(records[0].target, "db");
+    assert!(records[0].line.is_some());
+
+    #[cfg(feature = "kv")]
+    {
+        assert_eq!(records[0].message, "request{id=7}:query{table=users}: took 12ms");
+        let fields = [("rows", "3"), ("slow", "true")].map(|(key, value)| (key.to_owned(), value.to_owned()));
+        assert_eq!(records[0].fields, fields);
+    }
+    #[cfg(not(feature = "kv"))]
+    assert_eq!(
+        records[0].message,
+        "request{id=7}:query{table=users}: took 12ms rows=3 slow=true"
+    );
+}